@@ -0,0 +1,189 @@
+//! The client side of SCRAM (RFC 5802), without channel binding, as
+//! PostgreSQL and Kafka brokers authenticate with it.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ScramHash {
+    Sha256,
+    Sha512,
+}
+
+impl ScramHash {
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha256 => Sha256::digest(data).to_vec(),
+            ScramHash::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            ScramHash::Sha256 => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            ScramHash::Sha512 => {
+                let mut mac =
+                    Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// PBKDF2 with this HMAC, as `Hi` in RFC 5802.
+    fn hi(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        let mut block = salt.to_vec();
+        block.extend_from_slice(&1u32.to_be_bytes());
+        let mut u = self.hmac(password, &block);
+        let mut result = u.clone();
+        for _ in 1..iterations {
+            u = self.hmac(password, &u);
+            for (r, b) in result.iter_mut().zip(&u) {
+                *r ^= b;
+            }
+        }
+        result
+    }
+}
+
+pub(crate) struct Scram {
+    /// Names the source in errors.
+    scheme: &'static str,
+    hash: ScramHash,
+    nonce: String,
+    client_first_bare: String,
+    server_signature: Vec<u8>,
+}
+
+impl Scram {
+    pub fn new(scheme: &'static str, hash: ScramHash, user: &str) -> TransformerResult<Self> {
+        let mut random = [0u8; 18];
+        getrandom::getrandom(&mut random).map_err(|e| {
+            TransformerError::new(ErrorKind::Connection, format!("{scheme}: {e}")).with_source(e)
+        })?;
+        Ok(Self::with_nonce(scheme, hash, user, base64::encode(random)))
+    }
+
+    fn with_nonce(scheme: &'static str, hash: ScramHash, user: &str, nonce: String) -> Self {
+        let user = user.replace('=', "=3D").replace(',', "=2C");
+        Scram {
+            scheme,
+            hash,
+            client_first_bare: format!("n={user},r={nonce}"),
+            nonce,
+            server_signature: Vec::new(),
+        }
+    }
+
+    pub fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    pub fn client_final(
+        &mut self,
+        server_first: &str,
+        password: &str,
+    ) -> TransformerResult<String> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for part in server_first.split(',') {
+            match part.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = base64::decode(value).ok(),
+                Some(("i", value)) => iterations = value.parse::<u32>().ok(),
+                _ => {}
+            }
+        }
+        let (nonce, salt, iterations) = match (nonce, salt, iterations) {
+            (Some(nonce), Some(salt), Some(iterations)) if nonce.starts_with(&self.nonce) => {
+                (nonce, salt, iterations)
+            }
+            _ => {
+                return Err(TransformerError::new(
+                    ErrorKind::Source,
+                    format!(
+                        "{}: malformed message, SCRAM server-first-message",
+                        self.scheme
+                    ),
+                ))
+            }
+        };
+
+        let hash = self.hash;
+        let salted = hash.hi(password.as_bytes(), &salt, iterations);
+        let client_key = hash.hmac(&salted, b"Client Key");
+        let stored_key = hash.hash(&client_key);
+        let without_proof = format!("c=biws,r={nonce}");
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, without_proof
+        );
+        let client_signature = hash.hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+        let server_key = hash.hmac(&salted, b"Server Key");
+        self.server_signature = hash.hmac(&server_key, auth_message.as_bytes());
+        Ok(format!("{without_proof},p={}", base64::encode(proof)))
+    }
+
+    pub fn verify(&self, server_final: &str) -> TransformerResult<()> {
+        match server_final.strip_prefix("v=").map(base64::decode) {
+            Some(Ok(signature)) if signature == self.server_signature => Ok(()),
+            _ => Err(TransformerError::new(
+                ErrorKind::Config,
+                format!("{}: the server failed SCRAM authentication", self.scheme),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example exchange of RFC 7677.
+    #[test]
+    fn authenticates_as_in_rfc_7677() {
+        let mut scram = Scram::with_nonce(
+            "test",
+            ScramHash::Sha256,
+            "user",
+            "rOprNGfwEbeRWgbNEkqO".into(),
+        );
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            scram.client_final(server_first, "pencil").unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram
+            .verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+        let e = scram
+            .verify("v=7rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn refuses_a_server_nonce_it_did_not_start() {
+        let mut scram = Scram::with_nonce("test", ScramHash::Sha512, "a=b,c", "abc".into());
+        assert_eq!(scram.client_first(), "n,,n=a=3Db=2Cc,r=abc");
+        assert!(scram.client_final("r=xyz,s=c2FsdA==,i=1", "pw").is_err());
+        let e = scram.client_final("r=abcxyz,s=c2FsdA==", "pw").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Source);
+        assert!(scram.client_final("r=abcxyz,s=c2FsdA==,i=1", "pw").is_ok());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::TransformerResult;
+use mysql_async::prelude::*;
+use mysql_async::Pool;
+use std::sync::Arc;
+#[cfg(feature = "sqlite")]
+use tokio::sync::Mutex;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS mega_etl_checkpoint (
+    name VARCHAR(255) NOT NULL PRIMARY KEY,
+    position TEXT NOT NULL
+)";
+
+/// Where a source keeps its read position between runs, in a
+/// `mega_etl_checkpoint` table of the default sink. Sinks that cannot hold
+/// one keep the positions in memory only, so a restart starts over.
+pub(crate) enum Checkpoints {
+    Mysql(Arc<Pool>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+    Memory,
+}
+
+impl Checkpoints {
+    pub(super) async fn mysql(pool: Arc<Pool>) -> TransformerResult<Self> {
+        pool.get_conn().await?.query_drop(CREATE_TABLE).await?;
+        Ok(Checkpoints::Mysql(pool))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(super) async fn sqlite(conn: Arc<Mutex<rusqlite::Connection>>) -> TransformerResult<Self> {
+        conn.lock().await.execute_batch(CREATE_TABLE)?;
+        Ok(Checkpoints::Sqlite(conn))
+    }
+
+    pub async fn load(&self, name: &str) -> TransformerResult<Option<String>> {
+        match self {
+            Checkpoints::Mysql(pool) => Ok(pool
+                .get_conn()
+                .await?
+                .exec_first(
+                    "SELECT position FROM mega_etl_checkpoint WHERE name = ?",
+                    (name,),
+                )
+                .await?),
+            #[cfg(feature = "sqlite")]
+            Checkpoints::Sqlite(conn) => {
+                use rusqlite::OptionalExtension;
+                Ok(conn
+                    .lock()
+                    .await
+                    .query_row(
+                        "SELECT position FROM mega_etl_checkpoint WHERE name = ?1",
+                        [name],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            }
+            Checkpoints::Memory => Ok(None),
+        }
+    }
+
+    pub async fn save(&self, name: &str, position: &str) -> TransformerResult<()> {
+        match self {
+            Checkpoints::Mysql(pool) => {
+                pool.get_conn()
+                    .await?
+                    .exec_drop(
+                        "INSERT INTO mega_etl_checkpoint (name, position) VALUES (?, ?) \
+                         ON DUPLICATE KEY UPDATE position = VALUES(position)",
+                        (name, position),
+                    )
+                    .await?
+            }
+            #[cfg(feature = "sqlite")]
+            Checkpoints::Sqlite(conn) => {
+                conn.lock().await.execute(
+                    "INSERT INTO mega_etl_checkpoint (name, position) VALUES (?1, ?2) \
+                     ON CONFLICT(name) DO UPDATE SET position = excluded.position",
+                    [name, position],
+                )?;
+            }
+            Checkpoints::Memory => {}
+        }
+        Ok(())
+    }
+}
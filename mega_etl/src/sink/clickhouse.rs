@@ -0,0 +1,399 @@
+use crate::uri::{decode, parse_param};
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use url::Url;
+
+const DEFAULT_PORT: u16 = 8123;
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+/// How many batches may pend while ClickHouse cannot be reached.
+const DEFAULT_PENDING_BATCHES: usize = 10;
+
+/// Rows ClickHouse turned down, each with its error, which the failure policy
+/// of the sink deals with.
+pub(crate) type Rejected = Vec<(String, TransformerError)>;
+
+/// Writes the rows returned by `transform` into one ClickHouse table through
+/// its HTTP interface. Every row is a JSON object; rows are buffered and sent
+/// as a single `INSERT ... FORMAT JSONEachRow` once `batch_size` rows are
+/// pending or `flush_interval_ms` has passed. No more than `max_pending`
+/// rows are buffered, beyond which inserts fail until ClickHouse catches up.
+pub(crate) struct ClickHouseSink {
+    client: Client<HttpConnector>,
+    url: Url,
+    user: String,
+    password: String,
+    table: String,
+    batch_size: usize,
+    max_pending: usize,
+    rows: Mutex<Vec<String>>,
+    /// The rows turned down since the last `take_rejected`, as the records
+    /// they came from were already done.
+    rejected: Mutex<Rejected>,
+}
+
+impl ClickHouseSink {
+    pub fn connect(uri: &str) -> TransformerResult<Arc<Self>> {
+        let parsed = Url::parse(uri)?;
+        let host = parsed.host_str().ok_or(url::ParseError::EmptyHost)?;
+        let port = parsed.port().unwrap_or(DEFAULT_PORT);
+        let mut url = Url::parse(&format!("http://{host}:{port}/"))?;
+        let database = parsed.path().trim_start_matches('/');
+        if !database.is_empty() {
+            url.query_pairs_mut().append_pair("database", database);
+        }
+
+        let mut table = None;
+        let mut batch_size = DEFAULT_BATCH_SIZE;
+        let mut flush_interval_ms = DEFAULT_FLUSH_INTERVAL_MS;
+        let mut max_pending = None;
+        for (key, value) in parsed.query_pairs() {
+            match key.as_ref() {
+                "table" => table = Some(value.into_owned()),
+                "batch_size" => batch_size = parse_param("clickhouse", &key, &value)?,
+                "flush_interval_ms" => flush_interval_ms = parse_param("clickhouse", &key, &value)?,
+                "max_pending" => max_pending = Some(parse_param("clickhouse", &key, &value)?),
+                // anything else is a ClickHouse setting, e.g. `async_insert=1`
+                _ => {
+                    url.query_pairs_mut().append_pair(&key, &value);
+                }
+            }
+        }
+        let table = table.ok_or_else(|| {
+            TransformerError::new(ErrorKind::Config, "clickhouse: missing `table` parameter")
+        })?;
+        let batch_size = batch_size.max(1);
+        let max_pending = max_pending
+            .unwrap_or(batch_size * DEFAULT_PENDING_BATCHES)
+            .max(batch_size);
+
+        let user = match parsed.username() {
+            "" => "default".to_string(),
+            user => decode(user)?,
+        };
+        let password = decode(parsed.password().unwrap_or_default())?;
+
+        let sink = Arc::new(ClickHouseSink {
+            client: Client::new(),
+            url,
+            user,
+            password,
+            table,
+            batch_size,
+            max_pending,
+            rows: Mutex::new(Vec::new()),
+            rejected: Mutex::new(Vec::new()),
+        });
+        if flush_interval_ms > 0 {
+            spawn_flusher(
+                Arc::downgrade(&sink),
+                Duration::from_millis(flush_interval_ms),
+            );
+        }
+        Ok(sink)
+    }
+
+    pub async fn init(&self, sql_string: String) -> TransformerResult<()> {
+        self.post(None, sql_string).await
+    }
+
+    /// Buffers the rows, and sends them once a batch is full. The rows are
+    /// turned down when the buffer is full and cannot be sent.
+    pub async fn insert(&self, rows: Vec<String>) -> TransformerResult<()> {
+        let rows: Vec<String> = rows
+            .into_iter()
+            .map(|row| row.trim().to_string())
+            .filter(|row| !row.is_empty())
+            .collect();
+        if self.rows.lock().await.len() + rows.len() > self.max_pending {
+            match self.flush().await {
+                Err(e) if e.is_retryable() => {
+                    return Err(TransformerError::new(
+                        ErrorKind::Sink,
+                        format!("clickhouse: {} rows are pending", self.max_pending),
+                    )
+                    .with_source(e))
+                }
+                // the rows turned down left the buffer for `take_rejected`
+                _ => {}
+            }
+        }
+        let pending = {
+            let mut buffer = self.rows.lock().await;
+            buffer.extend(rows);
+            buffer.len()
+        };
+        if pending >= self.batch_size {
+            // the rows stay buffered, and make it with a later flush
+            if let Err(e) = self.flush().await {
+                log::warn!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends all pending rows. When ClickHouse cannot be reached, they stay
+    /// buffered and are sent again with the next flush. Rows it turns down
+    /// would fail the same way again, so they are kept for `take_rejected`,
+    /// and the flush fails.
+    pub async fn flush(&self) -> TransformerResult<()> {
+        let rows = std::mem::take(&mut *self.rows.lock().await);
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let e = match self.send(&rows).await {
+            Ok(()) => {
+                log::debug!("clickhouse: inserted {} rows", rows.len());
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        if e.is_retryable() {
+            self.requeue(rows).await;
+            return Err(e);
+        }
+        // one bad row fails the whole batch, so find it
+        log::warn!("{e}, inserting {} rows one by one", rows.len());
+        let mut rejected = 0;
+        for (i, row) in rows.iter().enumerate() {
+            match self.send(std::slice::from_ref(row)).await {
+                Ok(()) => {}
+                Err(e) if e.is_retryable() => {
+                    self.requeue(rows[i..].to_vec()).await;
+                    return Err(e);
+                }
+                Err(e) => {
+                    rejected += 1;
+                    self.rejected.lock().await.push((row.clone(), e));
+                }
+            }
+        }
+        match rejected {
+            0 => Ok(()),
+            n => Err(TransformerError::new(
+                ErrorKind::Sink,
+                format!("clickhouse: {n} rows rejected"),
+            )),
+        }
+    }
+
+    /// The rows turned down since the last call.
+    pub async fn take_rejected(&self) -> Rejected {
+        std::mem::take(&mut *self.rejected.lock().await)
+    }
+
+    async fn send(&self, rows: &[String]) -> TransformerResult<()> {
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.table);
+        self.post(Some(&query), rows.join("\n")).await
+    }
+
+    /// Puts rows back ahead of those buffered since they were taken.
+    async fn requeue(&self, mut rows: Vec<String>) {
+        let mut buffer = self.rows.lock().await;
+        rows.append(&mut buffer);
+        *buffer = rows;
+    }
+
+    async fn post(&self, query: Option<&str>, body: String) -> TransformerResult<()> {
+        let mut url = self.url.clone();
+        if let Some(query) = query {
+            url.query_pairs_mut().append_pair("query", query);
+        }
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header("X-ClickHouse-User", &self.user)
+            .header("X-ClickHouse-Key", &self.password)
+            .body(Body::from(body))?;
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+            // ClickHouse answers a 4xx for data or a query it cannot take,
+            // and a 5xx when it is in trouble
+            let kind = if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                ErrorKind::Sink
+            } else {
+                ErrorKind::Connection
+            };
+            return Err(TransformerError::new(
+                kind,
+                format!(
+                    "clickhouse: {status}: {}",
+                    String::from_utf8_lossy(&body).trim()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn spawn_flusher(sink: Weak<ClickHouseSink>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let sink = match sink.upgrade() {
+                Some(sink) => sink,
+                None => break,
+            };
+            if let Err(e) = sink.flush().await {
+                log::error!("{:?}", e.to_string());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    type Requests = Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// A stand-in for the HTTP interface of ClickHouse, which keeps the query
+    /// and the body of each request, and answers with `status`.
+    fn mock(status: fn(&str) -> StatusCode) -> (SocketAddr, Requests) {
+        let requests = Requests::default();
+        let kept = requests.clone();
+        let make_svc = make_service_fn(move |_| {
+            let kept = kept.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let kept = kept.clone();
+                    async move {
+                        let url = Url::parse(&format!("http://localhost{}", req.uri())).unwrap();
+                        let query = url
+                            .query_pairs()
+                            .find(|(key, _)| key == "query")
+                            .map(|(_, query)| query.into_owned())
+                            .unwrap_or_default();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        let mut resp = Response::new(Body::empty());
+                        *resp.status_mut() = status(&body);
+                        kept.lock().unwrap().push((query, body));
+                        Ok::<_, Infallible>(resp)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    fn sink(addr: SocketAddr, params: &str) -> Arc<ClickHouseSink> {
+        ClickHouseSink::connect(&format!(
+            "clickhouse://{addr}/analytics?table=orders&flush_interval_ms=0&{params}"
+        ))
+        .unwrap()
+    }
+
+    fn rows(rows: &[&str]) -> Vec<String> {
+        rows.iter().map(|row| row.to_string()).collect()
+    }
+
+    #[test]
+    fn inserts_full_batches_as_json_each_row() {
+        block_on(async {
+            let (addr, requests) = mock(|_| StatusCode::OK);
+            let sink = sink(addr, "batch_size=2");
+            sink.insert(rows(&[r#"{"id":1}"#, " "])).await.unwrap();
+            assert!(requests.lock().unwrap().is_empty());
+            sink.insert(rows(&[r#"{"id":2}"#, r#"{"id":3}"#]))
+                .await
+                .unwrap();
+            sink.insert(rows(&[r#"{"id":4}"#])).await.unwrap();
+            sink.flush().await.unwrap();
+            let requests = requests.lock().unwrap();
+            assert_eq!(
+                *requests,
+                vec![
+                    (
+                        "INSERT INTO orders FORMAT JSONEachRow".to_string(),
+                        "{\"id\":1}\n{\"id\":2}\n{\"id\":3}".to_string()
+                    ),
+                    (
+                        "INSERT INTO orders FORMAT JSONEachRow".to_string(),
+                        "{\"id\":4}".to_string()
+                    ),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn keeps_the_rows_clickhouse_rejects() {
+        block_on(async {
+            let (addr, requests) = mock(|body| {
+                if body.contains("bad") {
+                    StatusCode::BAD_REQUEST
+                } else {
+                    StatusCode::OK
+                }
+            });
+            let sink = sink(addr, "batch_size=10");
+            sink.insert(rows(&[r#"{"id":1}"#, r#"{"id":"bad"}"#, r#"{"id":3}"#]))
+                .await
+                .unwrap();
+            let e = sink.flush().await.unwrap_err();
+            assert!(!e.is_retryable());
+            assert!(
+                e.to_string().ends_with("clickhouse: 1 rows rejected"),
+                "{e}"
+            );
+            let bodies: Vec<String> = requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, body)| body.clone())
+                .collect();
+            assert_eq!(bodies.len(), 4);
+            assert_eq!(
+                bodies[1..],
+                ["{\"id\":1}", "{\"id\":\"bad\"}", "{\"id\":3}"]
+            );
+            assert!(sink.rows.lock().await.is_empty());
+            let rejected = sink.take_rejected().await;
+            assert_eq!(rejected.len(), 1);
+            assert_eq!(rejected[0].0, r#"{"id":"bad"}"#);
+            assert_eq!(rejected[0].1.kind(), ErrorKind::Sink);
+            assert!(sink.take_rejected().await.is_empty());
+        });
+    }
+
+    #[test]
+    fn keeps_rows_while_clickhouse_is_down_up_to_max_pending() {
+        block_on(async {
+            let (addr, requests) = mock(|_| StatusCode::SERVICE_UNAVAILABLE);
+            let sink = sink(addr, "batch_size=2&max_pending=3");
+            sink.insert(rows(&[r#"{"id":1}"#, r#"{"id":2}"#]))
+                .await
+                .unwrap();
+            assert!(sink.flush().await.unwrap_err().is_retryable());
+            let e = sink.insert(rows(&[r#"{"id":3}"#, r#"{"id":4}"#])).await;
+            assert!(e.unwrap_err().is_retryable());
+            assert_eq!(
+                *sink.rows.lock().await,
+                rows(&[r#"{"id":1}"#, r#"{"id":2}"#])
+            );
+            assert_eq!(requests.lock().unwrap().len(), 3);
+        });
+    }
+}
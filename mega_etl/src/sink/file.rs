@@ -0,0 +1,31 @@
+use crate::TransformerResult;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Appends every output as one line to a local file.
+pub(crate) struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn connect(path: &str) -> TransformerResult<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Arc::new(FileSink {
+            file: Mutex::new(file),
+        }))
+    }
+
+    pub async fn append(&self, lines: Vec<String>) -> TransformerResult<()> {
+        let mut buf = String::new();
+        for line in lines {
+            buf.push_str(line.trim_end_matches('\n'));
+            buf.push('\n');
+        }
+        let mut file = self.file.lock().await;
+        file.write_all(buf.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use crate::source::kafka::{Bootstrap, KafkaOptions};
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use chrono::Utc;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::record::Record;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Produces every output as one record on partition 0 of a topic.
+pub(crate) struct KafkaSink {
+    partition_client: PartitionClient,
+    /// Keeps the relay to the brokers up, if there is one.
+    _bootstrap: Bootstrap,
+}
+
+impl KafkaSink {
+    pub async fn connect(opts: &KafkaOptions) -> TransformerResult<Arc<Self>> {
+        let bootstrap = opts.bootstrap()?;
+        let client = tokio::time::timeout(
+            std::time::Duration::from_secs(3),
+            bootstrap.client_builder().build(),
+        )
+        .await
+        .map_err(|_| TransformerError::new(ErrorKind::Connection, "kafka: cannot connect in 3s"))?
+        .map_err(|e| TransformerError::new(ErrorKind::Connection, format!("kafka: {e}")))?;
+        let partition_client = client
+            .partition_client(opts.topic.clone(), 0, UnknownTopicHandling::Retry)
+            .await?;
+        Ok(Arc::new(KafkaSink {
+            partition_client,
+            _bootstrap: bootstrap,
+        }))
+    }
+
+    pub async fn produce(&self, values: Vec<String>) -> TransformerResult<()> {
+        let records = values
+            .into_iter()
+            .map(|value| Record {
+                key: None,
+                value: Some(value.into_bytes()),
+                headers: BTreeMap::new(),
+                timestamp: Utc::now(),
+            })
+            .collect();
+        self.partition_client
+            .produce(records, Compression::NoCompression)
+            .await?;
+        Ok(())
+    }
+}
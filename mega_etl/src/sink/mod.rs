@@ -0,0 +1,585 @@
+mod checkpoint;
+mod clickhouse;
+mod file;
+mod kafka;
+mod mysql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub(crate) use checkpoint::Checkpoints;
+
+use crate::{DataSource, ErrorKind, FailurePolicy, Output, TransformerError, TransformerResult};
+use clickhouse::{ClickHouseSink, Rejected};
+use file::FileSink;
+use futures_util::future::join_all;
+use kafka::KafkaSink;
+use mysql::MysqlSink;
+use mysql_async::Conn;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+pub(crate) const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub(crate) struct SinkConfig {
+    pub name: String,
+    pub uri: String,
+    pub policy: FailurePolicy,
+}
+
+pub(crate) enum DataSink {
+    Mysql(Arc<MysqlSink>),
+    ClickHouse(Arc<ClickHouseSink>),
+    Kafka(Arc<KafkaSink>),
+    File(Arc<FileSink>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+}
+
+/// A handle on the sink that one handler (a HTTP connection, a Kafka record)
+/// writes through.
+#[derive(Clone)]
+pub(crate) enum SinkConn {
+    Mysql(Arc<MysqlSink>, Arc<Mutex<Conn>>),
+    ClickHouse(Arc<ClickHouseSink>),
+    Kafka(Arc<KafkaSink>),
+    File(Arc<FileSink>),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+}
+
+impl DataSink {
+    pub async fn connect(uri: &str) -> TransformerResult<Self> {
+        match uri.split_once("://") {
+            Some(("mysql", _)) => Ok(DataSink::Mysql(MysqlSink::connect(uri)?)),
+            Some(("clickhouse", _)) => Ok(DataSink::ClickHouse(ClickHouseSink::connect(uri)?)),
+            Some(("kafka" | "kafkas", _)) => match DataSource::parse_uri(uri)? {
+                DataSource::Kafka(opts) => Ok(DataSink::Kafka(KafkaSink::connect(&opts).await?)),
+                _ => Err(TransformerError::new(
+                    ErrorKind::Config,
+                    format!("Unknown data sink: {uri}"),
+                )),
+            },
+            Some(("file", path)) => Ok(DataSink::File(FileSink::connect(path)?)),
+            #[cfg(feature = "sqlite")]
+            Some(("sqlite", path)) => {
+                if path.is_empty() {
+                    return Err(TransformerError::new(
+                        ErrorKind::Config,
+                        "sqlite: empty database path",
+                    ));
+                }
+                Ok(DataSink::Sqlite(sqlite::connect(path)?))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            Some(("sqlite", _)) => Err(TransformerError::new(
+                ErrorKind::Config,
+                "sqlite sink requires the `sqlite` feature",
+            )),
+            _ => Err(TransformerError::new(
+                ErrorKind::Config,
+                format!("Unknown data sink: {uri}"),
+            )),
+        }
+    }
+
+    pub async fn init(&self, sql_string: String) -> TransformerResult<()> {
+        match self {
+            DataSink::Mysql(sink) => {
+                let conn = Mutex::new(sink.get_conn().await?);
+                sink.execute(&conn, vec![sql_string]).await
+            }
+            DataSink::ClickHouse(sink) => sink.init(sql_string).await,
+            DataSink::Kafka(_) | DataSink::File(_) => Ok(()),
+            #[cfg(feature = "sqlite")]
+            DataSink::Sqlite(conn) => sqlite::init(conn, sql_string).await,
+        }
+    }
+
+    /// Writes out the rows a sink still buffers.
+    pub async fn flush(&self) -> TransformerResult<()> {
+        match self {
+            DataSink::ClickHouse(sink) => sink.flush().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The rows a buffering sink turned down after the records they came from
+    /// were done.
+    async fn take_rejected(&self) -> Rejected {
+        match self {
+            DataSink::ClickHouse(sink) => sink.take_rejected().await,
+            _ => Vec::new(),
+        }
+    }
+
+    pub async fn get_conn(&self) -> TransformerResult<SinkConn> {
+        match self {
+            DataSink::Mysql(sink) => Ok(SinkConn::Mysql(
+                sink.clone(),
+                Arc::new(Mutex::new(sink.get_conn().await?)),
+            )),
+            DataSink::ClickHouse(sink) => Ok(SinkConn::ClickHouse(sink.clone())),
+            DataSink::Kafka(sink) => Ok(SinkConn::Kafka(sink.clone())),
+            DataSink::File(sink) => Ok(SinkConn::File(sink.clone())),
+            #[cfg(feature = "sqlite")]
+            DataSink::Sqlite(conn) => Ok(SinkConn::Sqlite(conn.clone())),
+        }
+    }
+}
+
+impl SinkConn {
+    pub async fn execute(&self, sql_strings: Vec<String>) -> TransformerResult<()> {
+        match self {
+            SinkConn::Mysql(sink, conn) => sink.execute(conn, sql_strings).await,
+            SinkConn::ClickHouse(sink) => sink.insert(sql_strings).await,
+            SinkConn::Kafka(sink) => sink.produce(sql_strings).await,
+            SinkConn::File(sink) => sink.append(sql_strings).await,
+            #[cfg(feature = "sqlite")]
+            SinkConn::Sqlite(conn) => sqlite::execute(conn, sql_strings).await,
+        }
+    }
+
+    async fn take_rejected(&self) -> Rejected {
+        match self {
+            SinkConn::ClickHouse(sink) => sink.take_rejected().await,
+            _ => Vec::new(),
+        }
+    }
+
+    fn mysql(&self) -> Option<Arc<Mutex<Conn>>> {
+        match self {
+            SinkConn::Mysql(_, conn) => Some(conn.clone()),
+            _ => None,
+        }
+    }
+}
+
+struct NamedSink {
+    name: String,
+    policy: FailurePolicy,
+    sink: DataSink,
+    dead_letter: Option<DataSink>,
+}
+
+/// All sinks of a pipe, in the order they were added. The first one is the
+/// default sink given to `Pipe::new`.
+pub(crate) struct Sinks {
+    sinks: Vec<NamedSink>,
+}
+
+impl Sinks {
+    pub async fn connect(configs: &[SinkConfig]) -> TransformerResult<Self> {
+        let mut sinks = Vec::with_capacity(configs.len());
+        for config in configs {
+            let dead_letter = match &config.policy {
+                FailurePolicy::DeadLetter(uri) => Some(DataSink::connect(uri).await?),
+                _ => None,
+            };
+            sinks.push(NamedSink {
+                name: config.name.clone(),
+                policy: config.policy.clone(),
+                sink: DataSink::connect(&config.uri).await?,
+                dead_letter,
+            });
+        }
+        Ok(Sinks { sinks })
+    }
+
+    /// Runs the `init()` statement on the default sink.
+    pub async fn init(&self, sql_string: String) -> TransformerResult<()> {
+        match self.sinks.first() {
+            Some(default) => default.sink.init(sql_string).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the `init()` statement on the sink with this name.
+    pub async fn init_on(&self, name: &str, sql_string: String) -> TransformerResult<()> {
+        match self.sinks.iter().find(|sink| sink.name == name) {
+            Some(sink) => sink.sink.init(sql_string).await,
+            None => Err(TransformerError::new(
+                ErrorKind::Config,
+                format!("Unknown sink: {name}"),
+            )),
+        }
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.sinks.iter().any(|sink| sink.name == name)
+    }
+
+    /// Writes out what the sinks still buffer, before the pipe stops. The
+    /// rows a sink turns down go through its failure policy.
+    pub async fn flush(&self) -> TransformerResult<()> {
+        for sink in &self.sinks {
+            match sink.sink.flush().await {
+                Err(e) if e.is_retryable() => return Err(e),
+                // the rows turned down are dealt with below
+                _ => {}
+            }
+            let rejected = sink.sink.take_rejected().await;
+            if !rejected.is_empty() {
+                let conn = NamedConn {
+                    name: sink.name.clone(),
+                    policy: sink.policy.clone(),
+                    conn: sink.sink.get_conn().await?,
+                    dead_letter: match &sink.dead_letter {
+                        Some(dead_letter) => Some(dead_letter.get_conn().await?),
+                        None => None,
+                    },
+                };
+                conn.rejected(rejected).await?;
+            }
+            if let Some(dead_letter) = &sink.dead_letter {
+                dead_letter.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the read position of a source once the rows the sinks buffer
+    /// are written out, so the position never runs ahead of the data.
+    pub async fn commit(
+        &self,
+        checkpoints: &Checkpoints,
+        name: &str,
+        position: &str,
+    ) -> TransformerResult<()> {
+        self.flush().await?;
+        checkpoints.save(name, position).await
+    }
+
+    /// The checkpoint store of the sources that resume where they stopped,
+    /// kept in the default sink when it is a database.
+    pub async fn checkpoints(&self) -> TransformerResult<Checkpoints> {
+        match self.sinks.first().map(|default| &default.sink) {
+            Some(DataSink::Mysql(sink)) => Checkpoints::mysql(sink.pool.clone()).await,
+            #[cfg(feature = "sqlite")]
+            Some(DataSink::Sqlite(conn)) => Checkpoints::sqlite(conn.clone()).await,
+            _ => {
+                log::warn!("the default sink cannot hold checkpoints, keep them in memory");
+                Ok(Checkpoints::Memory)
+            }
+        }
+    }
+
+    pub async fn get_conn(&self) -> TransformerResult<SinksConn> {
+        let mut conns = Vec::with_capacity(self.sinks.len());
+        for sink in &self.sinks {
+            let dead_letter = match &sink.dead_letter {
+                Some(dead_letter) => Some(dead_letter.get_conn().await?),
+                None => None,
+            };
+            conns.push(NamedConn {
+                name: sink.name.clone(),
+                policy: sink.policy.clone(),
+                conn: sink.sink.get_conn().await?,
+                dead_letter,
+            });
+        }
+        Ok(SinksConn {
+            conns: Arc::new(conns),
+            route: None,
+        })
+    }
+
+    /// Like `get_conn`, but waits while a sink is out of reach, trying again
+    /// after a backoff that doubles up to a minute.
+    pub async fn wait_conn(&self) -> TransformerResult<SinksConn> {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.get_conn().await {
+                Err(e) if e.is_retryable() => {
+                    log::warn!("{e}, connecting again in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+struct NamedConn {
+    name: String,
+    policy: FailurePolicy,
+    conn: SinkConn,
+    dead_letter: Option<SinkConn>,
+}
+
+impl NamedConn {
+    async fn write(&self, batch: Vec<String>) -> TransformerResult<()> {
+        let failed = self.dead_letter.as_ref().map(|_| batch.clone());
+        match self.conn.execute(batch).await {
+            Ok(()) => self.rejected(self.conn.take_rejected().await).await,
+            Err(e) => self.fail(failed, e).await,
+        }
+    }
+
+    /// Puts the rows the sink turned down after their records were done
+    /// through the failure policy. With `FailurePolicy::Fail`, the record or
+    /// the commit at hand fails in their place, so a source does not save its
+    /// position past them.
+    async fn rejected(&self, rejected: Rejected) -> TransformerResult<()> {
+        let mut first = None;
+        for (row, e) in rejected {
+            let failed = self.dead_letter.as_ref().map(|_| vec![row]);
+            if let Err(e) = self.fail(failed, e).await {
+                first.get_or_insert(e);
+            }
+        }
+        first.map_or(Ok(()), Err)
+    }
+
+    /// Deals with a batch the sink failed to write, as the failure policy
+    /// says. `failed` is the batch, kept only for a dead letter sink.
+    async fn fail(
+        &self,
+        failed: Option<Vec<String>>,
+        e: TransformerError,
+    ) -> TransformerResult<()> {
+        match (&self.policy, &self.dead_letter, failed) {
+            (FailurePolicy::DeadLetter(_), Some(dead_letter), Some(batch)) => {
+                log::warn!("sink {}: {}, writing to dead letter", self.name, e);
+                let record = serde_json::json!({
+                    "sink": self.name,
+                    "error": e.to_string(),
+                    "reason": e.reason(),
+                    "retryable": e.is_retryable(),
+                    "data": batch,
+                });
+                dead_letter.execute(vec![record.to_string()]).await
+            }
+            (FailurePolicy::BestEffort, _, _) => {
+                log::warn!("sink {}: {}", self.name, e);
+                Ok(())
+            }
+            _ => Err(
+                TransformerError::new(ErrorKind::Sink, format!("sink {}: {}", self.name, e))
+                    .with_reason(e.reason())
+                    .with_source(e),
+            ),
+        }
+    }
+}
+
+/// The connections of all sinks that one handler writes through.
+#[derive(Clone)]
+pub(crate) struct SinksConn {
+    conns: Arc<Vec<NamedConn>>,
+    /// The sink outputs that name none go to, instead of all sinks.
+    route: Option<String>,
+}
+
+impl SinksConn {
+    /// The same connections, with the outputs that name no sink going to
+    /// `sink` only.
+    pub fn routed_to(&self, sink: Option<&str>) -> SinksConn {
+        SinksConn {
+            conns: self.conns.clone(),
+            route: sink.map(str::to_string),
+        }
+    }
+
+    /// Writes every output to its sink, or to all sinks if it names none. The
+    /// sinks are written concurrently, so a slow sink does not hold up the
+    /// others, and the record fails if any sink with `FailurePolicy::Fail`
+    /// does.
+    pub async fn write(&self, outputs: Vec<Output>) -> TransformerResult<()> {
+        let mut batches = vec![Vec::new(); self.conns.len()];
+        for output in outputs {
+            match output.sink.or_else(|| self.route.clone()) {
+                Some(name) => {
+                    let index = self
+                        .conns
+                        .iter()
+                        .position(|conn| conn.name == name)
+                        .ok_or_else(|| {
+                            // the transformer addressed a sink the pipe does not have
+                            TransformerError::new(
+                                ErrorKind::Transform,
+                                format!("Unknown sink: {name}"),
+                            )
+                        })?;
+                    batches[index].push(output.data);
+                }
+                None => {
+                    for batch in batches.iter_mut() {
+                        batch.push(output.data.clone());
+                    }
+                }
+            }
+        }
+        let writes = self
+            .conns
+            .iter()
+            .zip(batches)
+            .filter(|(_, batch)| !batch.is_empty())
+            .map(|(conn, batch)| conn.write(batch));
+        for result in join_all(writes).await {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// The connection handed to `Transformer::transform_save`, which only
+    /// exists when the default sink, or the sink of the route, is MySQL.
+    pub fn mysql(&self) -> Option<Arc<Mutex<Conn>>> {
+        let conn = match &self.route {
+            Some(route) => self.conns.iter().find(|conn| &conn.name == route),
+            None => self.conns.first(),
+        };
+        conn.and_then(|conn| conn.conn.mysql())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_SINK;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn file_sink(name: &str, path: &str) -> SinkConfig {
+        std::fs::create_dir_all("target/test-sinks").unwrap();
+        let _ = std::fs::remove_file(path);
+        SinkConfig {
+            name: name.into(),
+            uri: format!("file://{path}"),
+            policy: FailurePolicy::Fail,
+        }
+    }
+
+    fn lines(path: &str) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn routes_outputs_to_their_sinks() {
+        let (default, audit) = ("target/test-sinks/default", "target/test-sinks/audit");
+        block_on(async {
+            let sinks =
+                Sinks::connect(&[file_sink(DEFAULT_SINK, default), file_sink("audit", audit)])
+                    .await?;
+            let conn = sinks.get_conn().await?;
+            conn.write(vec![
+                Output::to("audit", "to audit"),
+                Output::broadcast("to all"),
+            ])
+            .await?;
+            conn.routed_to(Some("audit"))
+                .write(vec![Output::broadcast("routed")])
+                .await
+        })
+        .unwrap();
+        assert_eq!(lines(default), ["to all"]);
+        assert_eq!(lines(audit), ["to audit", "to all", "routed"]);
+    }
+
+    #[test]
+    fn fails_outputs_to_an_unknown_sink() {
+        let default = "target/test-sinks/unknown";
+        let e = block_on(async {
+            let sinks = Sinks::connect(&[file_sink(DEFAULT_SINK, default)]).await?;
+            let conn = sinks.get_conn().await?;
+            conn.write(vec![Output::to("missing", "lost")]).await
+        })
+        .unwrap_err();
+        assert!(e.to_string().contains("Unknown sink: missing"));
+        assert_eq!(e.kind(), ErrorKind::Transform);
+        assert!(lines(default).is_empty());
+    }
+
+    #[test]
+    fn fails_to_init_an_unknown_sink() {
+        let e = block_on(async {
+            let sinks =
+                Sinks::connect(&[file_sink(DEFAULT_SINK, "target/test-sinks/init")]).await?;
+            sinks.init_on("missing", String::new()).await
+        })
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn rejects_unknown_sink_uris() {
+        for uri in [
+            "redis://localhost",
+            "kafka://broker/",
+            "kafkas://broker/t?cert=c.pem",
+        ] {
+            let e = block_on(DataSink::connect(uri)).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::Config, "{uri}");
+        }
+    }
+
+    /// A stand-in for ClickHouse, which turns down the rows that hold `bad`.
+    fn clickhouse(name: &str, policy: FailurePolicy) -> SinkConfig {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Body, Request, Response, Server, StatusCode};
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, std::convert::Infallible>(service_fn(|req: Request<Body>| async {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                let mut resp = Response::new(Body::empty());
+                if String::from_utf8_lossy(&body).contains("bad") {
+                    *resp.status_mut() = StatusCode::BAD_REQUEST;
+                }
+                Ok::<_, hyper::Error>(resp)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        SinkConfig {
+            name: name.into(),
+            uri: format!("clickhouse://{addr}/db?table=t&flush_interval_ms=0"),
+            policy,
+        }
+    }
+
+    #[test]
+    fn puts_rows_clickhouse_rejects_through_the_failure_policy() {
+        let dead_letter = "target/test-sinks/rejected";
+        let _ = std::fs::remove_file(dead_letter);
+        block_on(async {
+            let policy = FailurePolicy::DeadLetter(format!("file://{dead_letter}"));
+            let sinks = Sinks::connect(&[clickhouse(DEFAULT_SINK, policy)]).await?;
+            let conn = sinks.get_conn().await?;
+            conn.write(vec![Output::broadcast(r#"{"id":"bad"}"#)])
+                .await?;
+            conn.write(vec![Output::broadcast(r#"{"id":1}"#)]).await?;
+            sinks.commit(&Checkpoints::Memory, "test", "1").await
+        })
+        .unwrap();
+        let record: serde_json::Value = serde_json::from_str(&lines(dead_letter)[0]).unwrap();
+        assert_eq!(record["data"], serde_json::json!([r#"{"id":"bad"}"#]));
+        assert_eq!(record["retryable"], false);
+    }
+
+    #[test]
+    fn does_not_commit_past_rows_clickhouse_rejects() {
+        let e = block_on(async {
+            let sinks = Sinks::connect(&[clickhouse(DEFAULT_SINK, FailurePolicy::Fail)]).await?;
+            let conn = sinks.get_conn().await?;
+            conn.write(vec![Output::broadcast(r#"{"id":"bad"}"#)])
+                .await?;
+            sinks.commit(&Checkpoints::Memory, "test", "1").await
+        })
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Sink);
+        assert!(!e.is_retryable());
+        assert!(e.to_string().contains("clickhouse: 400"), "{e}");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::TransformerResult;
+use rusqlite::Connection;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub(crate) fn connect(path: &str) -> TransformerResult<Arc<Mutex<Connection>>> {
+    let conn = if path == ":memory:" {
+        Connection::open_in_memory()?
+    } else {
+        Connection::open(path)?
+    };
+    // WASI has no shared memory for the wal-index, so hold the database in
+    // exclusive locking mode and let SQLite keep the index on the heap.
+    conn.pragma_update(None, "locking_mode", "EXCLUSIVE")?;
+    let journal_mode: String =
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        log::warn!("sqlite: journal_mode is {journal_mode}, not wal");
+    }
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+pub(crate) async fn init(conn: &Mutex<Connection>, sql_string: String) -> TransformerResult<()> {
+    conn.lock().await.execute_batch(&sql_string)?;
+    Ok(())
+}
+
+/// Runs the statements of one record in a single transaction, so a record is
+/// either stored completely or not at all.
+pub(crate) async fn execute(
+    conn: &Mutex<Connection>,
+    sql_strings: Vec<String>,
+) -> TransformerResult<()> {
+    let mut conn = conn.lock().await;
+    let tx = conn.transaction()?;
+    for sql_string in sql_strings {
+        log::debug!("receive {sql_string:?}");
+        tx.execute_batch(&sql_string)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn database(name: &str) -> PathBuf {
+        let dir = PathBuf::from("target/test-sqlite");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        path
+    }
+
+    fn count(conn: &Mutex<Connection>) -> i64 {
+        conn.blocking_lock()
+            .query_row("SELECT COUNT(*) FROM orders", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn opens_in_wal_mode() {
+        let path = database("wal.db");
+        let conn = connect(path.to_str().unwrap()).unwrap();
+        let mode: String = conn
+            .blocking_lock()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+
+    #[test]
+    fn init_creates_tables() {
+        let conn = connect(":memory:").unwrap();
+        block_on(init(
+            &conn,
+            "CREATE TABLE orders (id INTEGER PRIMARY KEY, amount REAL);
+             CREATE INDEX orders_amount ON orders (amount);"
+                .into(),
+        ))
+        .unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+
+    #[test]
+    fn commits_the_statements_of_a_record() {
+        let path = database("commit.db");
+        let conn = connect(path.to_str().unwrap()).unwrap();
+        block_on(async {
+            init(&conn, "CREATE TABLE orders (id INTEGER PRIMARY KEY)".into()).await?;
+            execute(
+                &conn,
+                vec![
+                    "INSERT INTO orders VALUES (1)".into(),
+                    "INSERT INTO orders VALUES (2)".into(),
+                ],
+            )
+            .await
+        })
+        .unwrap();
+        assert_eq!(count(&conn), 2);
+    }
+
+    #[test]
+    fn rolls_back_a_record_that_fails() {
+        let conn = connect(":memory:").unwrap();
+        let result = block_on(async {
+            init(&conn, "CREATE TABLE orders (id INTEGER PRIMARY KEY)".into()).await?;
+            execute(
+                &conn,
+                vec![
+                    "INSERT INTO orders VALUES (1)".into(),
+                    "INSERT INTO orders VALUES (1)".into(),
+                ],
+            )
+            .await
+        });
+        assert!(result.is_err());
+        assert_eq!(count(&conn), 0);
+    }
+}
@@ -0,0 +1,498 @@
+//! Checks that a webhook request comes from a sender the server trusts:
+//! by its address, a shared token, or an HMAC signature of the body.
+
+use super::timer::parse_interval;
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderMap, HeaderName, AUTHORIZATION};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+/// How old a signed timestamp may be, against replays.
+const DEFAULT_TOLERANCE: Duration = Duration::from_secs(300);
+
+/// The ways webhook senders sign their requests with a shared secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SignatureScheme {
+    /// `X-Hub-Signature-256: sha256=<hex>` of the body.
+    Github,
+    /// `Stripe-Signature: t=<time>,v1=<hex>` of `<time>.<body>`.
+    Stripe,
+    /// `X-Shopify-Hmac-Sha256: <base64>` of the body.
+    Shopify,
+    /// `X-Slack-Signature: v0=<hex>` of `v0:<time>:<body>`, with the time
+    /// in `X-Slack-Request-Timestamp`.
+    Slack,
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+    scheme: SignatureScheme,
+    secret: Vec<u8>,
+    tolerance: Duration,
+}
+
+/// An address range of `allow_ips`, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+/// The checks a request must pass, all of those configured.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Auth {
+    token: Option<String>,
+    api_key: Option<String>,
+    api_key_header: Option<HeaderName>,
+    signature: Option<Signature>,
+    allow_ips: Vec<IpRange>,
+}
+
+impl Auth {
+    /// Reads `token=..` for `Authorization: Bearer`, `api_key=..` with
+    /// `api_key_header=X-API-Key`, `signature=github|stripe|shopify|slack`
+    /// with `signature_secret=..` and `tolerance=300s`, and
+    /// `allow_ips=10.0.0.0/8,192.0.2.1`, ignoring other parameters.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let mut auth = Auth::default();
+        let mut api_key_header = DEFAULT_API_KEY_HEADER.to_string();
+        let mut scheme = None;
+        let mut secret = None;
+        let mut tolerance = DEFAULT_TOLERANCE;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "token" => auth.token = Some(value.into_owned()),
+                "api_key" => auth.api_key = Some(value.into_owned()),
+                "api_key_header" => api_key_header = value.into_owned(),
+                "signature" => {
+                    scheme = Some(match value.as_ref() {
+                        "github" => SignatureScheme::Github,
+                        "stripe" => SignatureScheme::Stripe,
+                        "shopify" => SignatureScheme::Shopify,
+                        "slack" => SignatureScheme::Slack,
+                        _ => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Config,
+                                format!(
+                                    "http: invalid signature {value:?}, \
+                                     use github, stripe, shopify or slack"
+                                ),
+                            ))
+                        }
+                    })
+                }
+                "signature_secret" => secret = Some(value.into_owned().into_bytes()),
+                "tolerance" => tolerance = parse_interval("http", &value)?,
+                "allow_ips" => {
+                    auth.allow_ips = value
+                        .split(',')
+                        .filter(|range| !range.is_empty())
+                        .map(IpRange::parse)
+                        .collect::<TransformerResult<_>>()?
+                }
+                _ => {}
+            }
+        }
+        let header = HeaderName::from_bytes(api_key_header.as_bytes()).map_err(|_| {
+            TransformerError::new(
+                ErrorKind::Config,
+                format!("http: invalid api_key_header {api_key_header:?}"),
+            )
+        })?;
+        auth.api_key_header = Some(header);
+        auth.signature = match (scheme, secret) {
+            (Some(scheme), Some(secret)) => Some(Signature {
+                scheme,
+                secret,
+                tolerance,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(TransformerError::new(
+                    ErrorKind::Config,
+                    "http: signature and signature_secret go together",
+                ))
+            }
+        };
+        Ok(auth)
+    }
+
+    /// Whether requests from this address are let in at all.
+    pub fn check_peer(&self, ip: IpAddr) -> Result<(), String> {
+        if self.allow_ips.is_empty() || self.allow_ips.iter().any(|range| range.contains(ip)) {
+            Ok(())
+        } else {
+            Err(format!("{ip} is not in allow_ips"))
+        }
+    }
+
+    /// Checks the token and API key, before the body is read.
+    pub fn check_headers(&self, headers: &HeaderMap) -> Result<(), String> {
+        if let Some(token) = &self.token {
+            let bearer = header(headers, AUTHORIZATION.as_str())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or("missing bearer token")?;
+            if !constant_time_eq(bearer.trim().as_bytes(), token.as_bytes()) {
+                return Err("invalid bearer token".into());
+            }
+        }
+        if let (Some(key), Some(name)) = (&self.api_key, &self.api_key_header) {
+            let value = header(headers, name.as_str()).ok_or(format!("missing {name}"))?;
+            if !constant_time_eq(value.as_bytes(), key.as_bytes()) {
+                return Err(format!("invalid {name}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The API key or bearer token a request came with, which tells its
+    /// client apart from others, if it is one the server checks. Values the
+    /// server does not check are made up by the client, so they do not count.
+    pub fn credential<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        let api_key = match (&self.api_key, &self.api_key_header) {
+            (Some(key), Some(name)) => header(headers, name.as_str())
+                .filter(|value| constant_time_eq(value.as_bytes(), key.as_bytes())),
+            _ => None,
+        };
+        let bearer = match &self.token {
+            Some(token) => header(headers, AUTHORIZATION.as_str())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::trim)
+                .filter(|bearer| constant_time_eq(bearer.as_bytes(), token.as_bytes())),
+            None => None,
+        };
+        api_key.or(bearer)
+    }
+
+    /// Checks the signature of the body.
+    pub fn check_body(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), String> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Ok(()),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&signature.secret)
+            .map_err(|e| format!("invalid signature_secret: {e}"))?;
+        let expected: Vec<Vec<u8>> = match signature.scheme {
+            SignatureScheme::Github => {
+                let value =
+                    header(headers, "x-hub-signature-256").ok_or("missing X-Hub-Signature-256")?;
+                mac.update(body);
+                value
+                    .strip_prefix("sha256=")
+                    .and_then(from_hex)
+                    .into_iter()
+                    .collect()
+            }
+            SignatureScheme::Shopify => {
+                let value = header(headers, "x-shopify-hmac-sha256")
+                    .ok_or("missing X-Shopify-Hmac-Sha256")?;
+                mac.update(body);
+                base64::decode(value.trim()).ok().into_iter().collect()
+            }
+            SignatureScheme::Stripe => {
+                let value =
+                    header(headers, "stripe-signature").ok_or("missing Stripe-Signature")?;
+                let mut timestamp = None;
+                let mut signatures = Vec::new();
+                for part in value.split(',') {
+                    match part.trim().split_once('=') {
+                        Some(("t", t)) => timestamp = Some(t),
+                        Some(("v1", v1)) => signatures.extend(from_hex(v1)),
+                        _ => {}
+                    }
+                }
+                let timestamp = timestamp.ok_or("missing timestamp in Stripe-Signature")?;
+                signature.check_time(timestamp)?;
+                mac.update(timestamp.as_bytes());
+                mac.update(b".");
+                mac.update(body);
+                signatures
+            }
+            SignatureScheme::Slack => {
+                let timestamp = header(headers, "x-slack-request-timestamp")
+                    .ok_or("missing X-Slack-Request-Timestamp")?;
+                let value =
+                    header(headers, "x-slack-signature").ok_or("missing X-Slack-Signature")?;
+                signature.check_time(timestamp)?;
+                mac.update(format!("v0:{timestamp}:").as_bytes());
+                mac.update(body);
+                value
+                    .strip_prefix("v0=")
+                    .and_then(from_hex)
+                    .into_iter()
+                    .collect()
+            }
+        };
+        // the MAC compares in constant time
+        if expected
+            .iter()
+            .any(|expected| mac.clone().verify_slice(expected).is_ok())
+        {
+            Ok(())
+        } else {
+            Err("invalid signature".into())
+        }
+    }
+}
+
+impl Signature {
+    fn check_time(&self, timestamp: &str) -> Result<(), String> {
+        let signed: u64 = timestamp
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid timestamp {timestamp:?}"))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(signed) > self.tolerance.as_secs() {
+            return Err(format!("timestamp {signed} is out of tolerance"));
+        }
+        Ok(())
+    }
+}
+
+impl IpRange {
+    fn parse(range: &str) -> TransformerResult<Self> {
+        let invalid = || {
+            TransformerError::new(
+                ErrorKind::Config,
+                format!("http: invalid allow_ips range {range:?}"),
+            )
+        };
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(IpRange { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 peer of a dual-stack listener shows up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    /// The example of the GitHub documentation.
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    const HEX: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn auth(query: &str) -> Auth {
+        Auth::parse(&Url::parse(&format!("http://0.0.0.0:3344/?{query}")).unwrap()).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn parses_options() {
+        let auth = auth("token=t&api_key=k&api_key_header=X-Key&signature=github&signature_secret=s&allow_ips=10.0.0.0/8,,::1");
+        assert_eq!(auth.token.as_deref(), Some("t"));
+        assert_eq!(auth.api_key_header.unwrap().as_str(), "x-key");
+        assert_eq!(auth.signature.unwrap().scheme, SignatureScheme::Github);
+        assert_eq!(auth.allow_ips.len(), 2);
+
+        let parse = |query: &str| Auth::parse(&Url::parse(&format!("http://h/?{query}")).unwrap());
+        assert!(parse("signature=github").is_err());
+        assert!(parse("signature_secret=s").is_err());
+        assert!(parse("signature=gitlab&signature_secret=s").is_err());
+        assert!(parse("api_key_header=X%20Key").is_err());
+        for range in ["10.0.0.0/33", "::1/129", "10.0.0", "10.0.0.0/x"] {
+            assert!(IpRange::parse(range).is_err(), "{range}");
+        }
+    }
+
+    #[test]
+    fn matches_address_ranges() {
+        let range = |range| IpRange::parse(range).unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(range("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!range("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(range("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!range("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!range("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(range("::/0").contains(ip("::1")));
+        assert!(!range("::/0").contains(ip("127.0.0.1")));
+
+        let auth = auth("allow_ips=10.0.0.0/8");
+        assert!(auth.check_peer(ip("10.0.0.1")).is_ok());
+        assert!(auth.check_peer(ip("127.0.0.1")).is_err());
+        assert!(Auth::default().check_peer(ip("127.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(from_hex("00ff7A"), Some(vec![0, 255, 122]));
+        assert_eq!(from_hex(""), Some(vec![]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        // not split inside a multi-byte character
+        assert_eq!(from_hex("aéa"), None);
+    }
+
+    #[test]
+    fn checks_tokens_and_api_keys() {
+        let auth = auth("token=secret&api_key=key");
+        let valid = headers(&[("authorization", "Bearer secret"), ("x-api-key", "key")]);
+        assert!(auth.check_headers(&valid).is_ok());
+        for invalid in [
+            headers(&[("x-api-key", "key")]),
+            headers(&[("authorization", "Bearer secreT"), ("x-api-key", "key")]),
+            headers(&[("authorization", "Basic secret"), ("x-api-key", "key")]),
+            headers(&[("authorization", "Bearer secret"), ("x-api-key", "ke")]),
+        ] {
+            assert!(auth.check_headers(&invalid).is_err(), "{invalid:?}");
+        }
+        assert!(Auth::default().check_headers(&HeaderMap::new()).is_ok());
+    }
+
+    #[test]
+    fn counts_only_checked_credentials() {
+        let sent = headers(&[("authorization", "Bearer secret"), ("x-api-key", "key")]);
+        assert_eq!(Auth::default().credential(&sent), None);
+        assert_eq!(auth("token=secret").credential(&sent), Some("secret"));
+        assert_eq!(
+            auth("api_key=key&token=secret").credential(&sent),
+            Some("key")
+        );
+        assert_eq!(auth("token=other").credential(&sent), None);
+        assert_eq!(
+            auth("api_key=other&token=secret").credential(&sent),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn checks_github_and_shopify_signatures() {
+        let github = auth(&format!("signature=github&signature_secret={SECRET}"));
+        let signed = headers(&[("x-hub-signature-256", &format!("sha256={HEX}"))]);
+        assert!(github.check_body(&signed, BODY).is_ok());
+        assert!(github.check_body(&signed, b"Hello, World?").is_err());
+        assert!(github
+            .check_body(&headers(&[("x-hub-signature-256", HEX)]), BODY)
+            .is_err());
+        assert!(github.check_body(&HeaderMap::new(), BODY).is_err());
+
+        let shopify = auth(&format!("signature=shopify&signature_secret={SECRET}"));
+        let base64 = "dXEH6g6yUJ/CESIczphLijdXC211hsIsRvQ3nIsEPhc=";
+        let signed = headers(&[("x-shopify-hmac-sha256", base64)]);
+        assert!(shopify.check_body(&signed, BODY).is_ok());
+        assert!(shopify.check_body(&signed, b"").is_err());
+    }
+
+    #[test]
+    fn checks_slack_signatures_and_their_age() {
+        // the example of the Slack documentation, signed in 2018
+        let body = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+        let signed = headers(&[
+            ("x-slack-request-timestamp", "1531420618"),
+            (
+                "x-slack-signature",
+                "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503",
+            ),
+        ]);
+        let query = "signature=slack&signature_secret=8f742231b10e8888abcd99yyyzzz85a5";
+        let e = auth(query).check_body(&signed, body).unwrap_err();
+        assert!(e.contains("out of tolerance"), "{e}");
+        let slack = auth(&format!("{query}&tolerance=100000d"));
+        assert!(slack.check_body(&signed, body).is_ok());
+        assert!(slack.check_body(&signed, &body[1..]).is_err());
+    }
+
+    #[test]
+    fn checks_stripe_signatures() {
+        let stripe = auth("signature=stripe&signature_secret=whsec");
+        let sign = |t: u64| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"whsec").unwrap();
+            mac.update(format!("{t}.").as_bytes());
+            mac.update(BODY);
+            let hex: String = mac
+                .finalize()
+                .into_bytes()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            // an old secret signs too while it is rolled
+            format!("t={t},v1={},v1={hex},v0=ignored", "00".repeat(32))
+        };
+        let signed = |value: &str| headers(&[("stripe-signature", value)]);
+        assert!(stripe.check_body(&signed(&sign(now())), BODY).is_ok());
+        assert!(stripe
+            .check_body(&signed(&sign(now() - 600)), BODY)
+            .is_err());
+        assert!(stripe.check_body(&signed(&sign(now())), b"").is_err());
+        assert!(stripe
+            .check_body(&signed(&format!("v1={HEX}")), BODY)
+            .is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Splits a webhook request that carries many events into one item each, so
+//! the transformer runs per event.
+
+use super::body::Rejection;
+use super::file::{csv_record, split_csv_row};
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use hyper::StatusCode;
+use serde_json::Value;
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Split {
+    /// One item per element of a JSON array.
+    Json,
+    /// One item per line, each a JSON value.
+    Ndjson,
+    /// One item per row after the header row, as a JSON object keyed by the
+    /// header.
+    Csv,
+}
+
+impl Split {
+    /// Reads `split=json|ndjson|csv`. Requests are not split without it.
+    pub fn parse(url: &Url) -> TransformerResult<Option<Self>> {
+        let mut split = None;
+        for (key, value) in url.query_pairs() {
+            if key == "split" {
+                split = Some(match value.as_ref() {
+                    "json" => Split::Json,
+                    "ndjson" => Split::Ndjson,
+                    "csv" => Split::Csv,
+                    _ => {
+                        return Err(TransformerError::new(
+                            ErrorKind::Config,
+                            format!("http: invalid split {value:?}, use json, ndjson or csv"),
+                        ))
+                    }
+                });
+            }
+        }
+        Ok(split)
+    }
+
+    /// The items of the content, each of them or why it is not one. A body
+    /// that cannot be split at all is rejected as a whole.
+    pub fn items(self, content: &[u8]) -> Result<Vec<Result<Vec<u8>, String>>, Rejection> {
+        match self {
+            Split::Json => match serde_json::from_slice::<Value>(content) {
+                Ok(Value::Array(elements)) => Ok(elements
+                    .iter()
+                    .map(|element| Ok(element.to_string().into_bytes()))
+                    .collect()),
+                // a single event sent on its own
+                Ok(value) => Ok(vec![Ok(value.to_string().into_bytes())]),
+                Err(e) => Err(Rejection {
+                    status: StatusCode::BAD_REQUEST,
+                    reason: format!("invalid JSON: {e}"),
+                }),
+            },
+            Split::Ndjson => Ok(content
+                .split(|b| *b == b'\n')
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| match serde_json::from_slice::<Value>(line) {
+                    Ok(_) => Ok(line.to_vec()),
+                    Err(e) => Err(format!("invalid JSON: {e}")),
+                })
+                .collect()),
+            Split::Csv => {
+                let (header, mut consumed) = match split_csv_row(content, true) {
+                    Some(header) => header,
+                    None => return Ok(Vec::new()),
+                };
+                let mut items = Vec::new();
+                while let Some((row, len)) = split_csv_row(&content[consumed..], true) {
+                    items.extend(csv_record(Some(&header), row).map(Ok));
+                    consumed += len;
+                }
+                Ok(items)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(split: Split, content: &str) -> Vec<Result<String, String>> {
+        split
+            .items(content.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|item| item.map(|item| String::from_utf8(item).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let parse = |query: &str| Split::parse(&Url::parse(&format!("http://h/?{query}")).unwrap());
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(parse("split=json").unwrap(), Some(Split::Json));
+        assert_eq!(parse("split=ndjson").unwrap(), Some(Split::Ndjson));
+        assert_eq!(parse("split=csv").unwrap(), Some(Split::Csv));
+        assert!(parse("split=xml").is_err());
+    }
+
+    #[test]
+    fn splits_json_arrays() {
+        assert_eq!(
+            items(Split::Json, r#"[{"id":1}, 2, "three"]"#),
+            [
+                Ok(r#"{"id":1}"#.to_string()),
+                Ok("2".into()),
+                Ok(r#""three""#.into())
+            ]
+        );
+        assert_eq!(
+            items(Split::Json, r#"{"id":1}"#),
+            [Ok(r#"{"id":1}"#.to_string())]
+        );
+        assert!(items(Split::Json, "[]").is_empty());
+        let rejection = Split::Json.items(b"[1,").unwrap_err();
+        assert_eq!(rejection.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn splits_ndjson_lines() {
+        let items = items(
+            Split::Ndjson,
+            "{\"id\":1}\r\n\n  \n[2]\nnot json\n{\"id\":3}",
+        );
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0], Ok("{\"id\":1}".into()));
+        assert_eq!(items[1], Ok("[2]".into()));
+        assert!(items[2].as_ref().unwrap_err().starts_with("invalid JSON"));
+        assert_eq!(items[3], Ok("{\"id\":3}".into()));
+    }
+
+    #[test]
+    fn splits_csv_rows_keyed_by_the_header() {
+        let items = items(Split::Csv, "id,note\r\n1,\"a, \"\"b\"\"\nc\"\n\n2,d");
+        let values: Vec<Value> = items
+            .iter()
+            .map(|item| serde_json::from_str(item.as_ref().unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                serde_json::json!({"id": "1", "note": "a, \"b\"\nc"}),
+                serde_json::json!({"id": "2", "note": "d"}),
+            ]
+        );
+        assert!(Split::Csv.items(b"").unwrap().is_empty());
+        assert!(Split::Csv.items(b"id,note\n").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+//! Reads the body of a webhook request within a size limit, and decodes it
+//! by its `Content-Encoding`.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, StatusCode};
+use std::io::Read;
+use url::Url;
+
+const DEFAULT_MAX_BODY: usize = 10 * 1024 * 1024;
+
+/// Why a request body is not taken, and the status it is answered with.
+#[derive(Debug)]
+pub(crate) struct Rejection {
+    pub status: StatusCode,
+    pub reason: String,
+}
+
+impl Rejection {
+    fn new(status: StatusCode, reason: String) -> Self {
+        Rejection { status, reason }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BodyOptions {
+    /// The largest body taken, before and after decoding.
+    pub max_body: usize,
+    /// The media types taken, e.g. `application/json` or `text/*`, all of
+    /// them when empty.
+    pub content_types: Vec<String>,
+}
+
+impl Default for BodyOptions {
+    fn default() -> Self {
+        BodyOptions {
+            max_body: DEFAULT_MAX_BODY,
+            content_types: Vec::new(),
+        }
+    }
+}
+
+impl BodyOptions {
+    /// Reads `max_body=10mb`, in bytes or with a `kb`, `mb` or `gb` suffix,
+    /// and `content_types=application/json,text/*`, ignoring other
+    /// parameters.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let mut opts = BodyOptions::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "max_body" => opts.max_body = parse_size(&value)?,
+                "content_types" => {
+                    opts.content_types = value
+                        .split(',')
+                        .map(|media| media.trim().to_ascii_lowercase())
+                        .filter(|media| !media.is_empty())
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Checks the type and the announced size of the body, before it is read.
+    pub fn check_headers(&self, headers: &HeaderMap) -> Result<(), Rejection> {
+        if !self.content_types.is_empty() {
+            let media = headers
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(';').next())
+                .map(|media| media.trim().to_ascii_lowercase())
+                .unwrap_or_default();
+            if !self
+                .content_types
+                .iter()
+                .any(|taken| media_matches(taken, &media))
+            {
+                return Err(Rejection::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    format!("unsupported Content-Type {media:?}"),
+                ));
+            }
+        }
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if length.is_some_and(|length| length > self.max_body as u64) {
+            return Err(self.too_large());
+        }
+        Ok(())
+    }
+
+    /// Reads the body and undoes its `Content-Encoding`.
+    pub async fn read(&self, headers: &HeaderMap, mut body: Body) -> Result<Vec<u8>, Rejection> {
+        let mut content = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| {
+                Rejection::new(StatusCode::BAD_REQUEST, format!("cannot read body: {e}"))
+            })?;
+            if content.len() + chunk.len() > self.max_body {
+                return Err(self.too_large());
+            }
+            content.extend_from_slice(&chunk);
+        }
+
+        let mut encodings = Vec::new();
+        for value in headers.get_all(CONTENT_ENCODING) {
+            let value = value.to_str().map_err(|_| {
+                Rejection::new(StatusCode::BAD_REQUEST, "invalid Content-Encoding".into())
+            })?;
+            encodings.extend(
+                value
+                    .split(',')
+                    .map(|encoding| encoding.trim().to_ascii_lowercase())
+                    .filter(|encoding| !encoding.is_empty() && encoding != "identity"),
+            );
+        }
+        // the last encoding applied comes first off
+        for encoding in encodings.iter().rev() {
+            content = self.decode(encoding, &content)?;
+        }
+        Ok(content)
+    }
+
+    fn decode(&self, encoding: &str, data: &[u8]) -> Result<Vec<u8>, Rejection> {
+        let reader: Box<dyn Read + '_> = match encoding {
+            "gzip" | "x-gzip" => Box::new(MultiGzDecoder::new(data)),
+            // meant to be zlib, but some clients send raw deflate
+            "deflate" if is_zlib(data) => Box::new(ZlibDecoder::new(data)),
+            "deflate" => Box::new(DeflateDecoder::new(data)),
+            "br" => Box::new(brotli_decompressor::Decompressor::new(data, 4096)),
+            _ => {
+                return Err(Rejection::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    format!("unsupported Content-Encoding {encoding:?}"),
+                ))
+            }
+        };
+        let mut decoded = Vec::new();
+        // a small body can decode to a huge one
+        reader
+            .take(self.max_body as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| {
+                Rejection::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("cannot decode {encoding} body: {e}"),
+                )
+            })?;
+        if decoded.len() > self.max_body {
+            return Err(self.too_large());
+        }
+        Ok(decoded)
+    }
+
+    fn too_large(&self) -> Rejection {
+        Rejection::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("body larger than {} bytes", self.max_body),
+        )
+    }
+}
+
+fn media_matches(taken: &str, media: &str) -> bool {
+    match taken.strip_suffix("/*") {
+        Some(kind) => media
+            .split_once('/')
+            .is_some_and(|(media_kind, _)| media_kind == kind),
+        None => taken == media,
+    }
+}
+
+/// Whether the data starts with a zlib header, of a deflate stream.
+fn is_zlib(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+fn parse_size(value: &str) -> TransformerResult<usize> {
+    let invalid = || {
+        TransformerError::new(
+            ErrorKind::Config,
+            format!("http: invalid max_body {value:?}"),
+        )
+    };
+    let lower = value.trim().to_ascii_lowercase();
+    let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (lower.as_str(), ""),
+    };
+    let unit: usize = match unit {
+        "" | "b" => 1,
+        "kb" => 1 << 10,
+        "mb" => 1 << 20,
+        "gb" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let number: usize = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(unit).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use hyper::header::HeaderValue;
+    use std::io::Write;
+
+    const JSON: &[u8] = br#"{"a":1}"#;
+    /// `JSON` in an uncompressed brotli meta-block.
+    const BROTLI: [u8; 11] = [96, 0, 16, 123, 34, 97, 34, 58, 49, 125, 3];
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn options(query: &str) -> BodyOptions {
+        BodyOptions::parse(&Url::parse(&format!("http://0.0.0.0:3344/?{query}")).unwrap()).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(opts: &BodyOptions, encoding: &str, body: Vec<u8>) -> Result<Vec<u8>, Rejection> {
+        let headers = headers(&[("content-encoding", encoding)]);
+        block_on(opts.read(&headers, Body::from(body)))
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512b").unwrap(), 512);
+        assert_eq!(parse_size("1KB").unwrap(), 1024);
+        assert_eq!(parse_size(" 10mb ").unwrap(), 10 << 20);
+        assert_eq!(parse_size("2gb").unwrap(), 2 << 30);
+        for size in ["", "mb", "1tb", "1.5mb", "-1", "99999999999999999999gb"] {
+            assert!(parse_size(size).is_err(), "{size}");
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = options("max_body=1kb&content_types=Application/JSON,,text/*");
+        assert_eq!(opts.max_body, 1024);
+        assert_eq!(opts.content_types, ["application/json", "text/*"]);
+        assert_eq!(options("").max_body, DEFAULT_MAX_BODY);
+        assert!(BodyOptions::parse(&Url::parse("http://h/?max_body=big").unwrap()).is_err());
+    }
+
+    #[test]
+    fn matches_media_types() {
+        assert!(media_matches("application/json", "application/json"));
+        assert!(!media_matches("application/json", "application/jsonx"));
+        assert!(media_matches("text/*", "text/csv"));
+        assert!(!media_matches("text/*", "textual/csv"));
+        assert!(!media_matches("text/*", "text"));
+    }
+
+    #[test]
+    fn checks_headers() {
+        let opts = options("max_body=10&content_types=application/json,text/*");
+        for taken in ["application/json; charset=utf-8", "TEXT/plain"] {
+            assert!(opts
+                .check_headers(&headers(&[("content-type", taken)]))
+                .is_ok());
+        }
+        for (pairs, status) in [
+            (vec![], StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (
+                vec![("content-type", "application/xml")],
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                vec![("content-type", "text/csv"), ("content-length", "11")],
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let rejection = opts.check_headers(&headers(&pairs)).unwrap_err();
+            assert_eq!(rejection.status, status, "{pairs:?}");
+        }
+        assert!(options("").check_headers(&HeaderMap::new()).is_ok());
+    }
+
+    #[test]
+    fn decodes_bodies() {
+        let opts = options("");
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(JSON).unwrap();
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(JSON).unwrap();
+        for (encoding, body) in [
+            ("", JSON.to_vec()),
+            ("identity", JSON.to_vec()),
+            ("gzip", gzip(JSON)),
+            ("X-GZIP", gzip(JSON)),
+            ("deflate", zlib.finish().unwrap()),
+            ("deflate", deflate.finish().unwrap()),
+            ("br", BROTLI.to_vec()),
+            // gzip was applied last
+            ("br, gzip", gzip(&BROTLI)),
+        ] {
+            assert_eq!(read(&opts, encoding, body).unwrap(), JSON, "{encoding}");
+        }
+    }
+
+    #[test]
+    fn rejects_bodies_it_cannot_take() {
+        let opts = options("max_body=100");
+        for (encoding, body, status) in [
+            ("", vec![b' '; 101], StatusCode::PAYLOAD_TOO_LARGE),
+            // small on the wire, too large once decoded
+            ("gzip", gzip(&[0; 101]), StatusCode::PAYLOAD_TOO_LARGE),
+            ("gzip", JSON.to_vec(), StatusCode::BAD_REQUEST),
+            (
+                "compress",
+                JSON.to_vec(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+        ] {
+            let rejection = read(&opts, encoding, body).unwrap_err();
+            assert_eq!(rejection.status, status, "{encoding}");
+        }
+        assert_eq!(read(&opts, "gzip", gzip(&[0; 100])).unwrap().len(), 100);
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A row inserted, updated or deleted in a source database, as captured by
+/// a CDC source. It is handed to the transformer as JSON, so
+/// `serde_json::from_slice::<ChangeEvent>(inbound_data)` reads it back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub op: ChangeOp,
+    /// The MySQL database, or the PostgreSQL schema.
+    pub database: String,
+    pub table: String,
+    /// The row before an update or a delete, by column name.
+    pub before: Option<Map<String, Value>>,
+    /// The row after an insert or an update, by column name.
+    pub after: Option<Map<String, Value>>,
+    /// When the change was committed, in seconds since the epoch.
+    pub timestamp: u64,
+    /// Where the change is in the database log, e.g. a binlog file and
+    /// offset.
+    pub position: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
@@ -0,0 +1,676 @@
+//! Reads records from a local file as it grows, like `tail -F`, or from the
+//! files dropped into a directory. WASI has no file notifications, so both
+//! poll.
+
+use crate::uri::{decode, parse_param};
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+const DEFAULT_POLL_MS: u64 = 1000;
+const READ_SIZE: usize = 64 * 1024;
+/// How many bytes at the start of a file identify it, to tell a rotated file
+/// from the one that was read before.
+const FINGERPRINT_SIZE: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// One record per line.
+    Lines,
+    /// One record per row after the header row, as a JSON object keyed by
+    /// the header.
+    Csv,
+    /// One record per JSON value, or per element of a JSON array.
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FileOptions {
+    pub path: PathBuf,
+    pub format: Format,
+    pub extension: Option<String>,
+    pub poll_interval: Duration,
+    pub from_end: bool,
+}
+
+impl FileOptions {
+    /// Reads `file:///path?format=lines|csv|json&poll_ms=..&start=beginning|end`,
+    /// where `file://path` is relative like for the file sink. A path ending
+    /// with `/` is a directory, whose files are read in name order,
+    /// optionally only those with the given `extension`.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let path = decode(&format!("{}{}", url.host_str().unwrap_or(""), url.path()))?;
+        if path.is_empty() || path == "/" {
+            return Err(TransformerError::new(ErrorKind::Config, "file: empty path"));
+        }
+        let mut opts = FileOptions {
+            path: PathBuf::from(path),
+            format: Format::Lines,
+            extension: None,
+            poll_interval: Duration::from_millis(DEFAULT_POLL_MS),
+            from_end: false,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "format" => {
+                    opts.format = match value.as_ref() {
+                        "lines" => Format::Lines,
+                        "csv" => Format::Csv,
+                        "json" => Format::Json,
+                        _ => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Config,
+                                format!("file: invalid format {value:?}, use lines, csv or json"),
+                            ))
+                        }
+                    }
+                }
+                "extension" => opts.extension = Some(value.trim_start_matches('.').to_string()),
+                "poll_ms" => {
+                    opts.poll_interval = Duration::from_millis(parse_param("file", &key, &value)?)
+                }
+                "start" => {
+                    opts.from_end = match value.as_ref() {
+                        "beginning" => false,
+                        "end" => true,
+                        _ => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Config,
+                                format!("file: invalid start {value:?}, use beginning or end"),
+                            ))
+                        }
+                    }
+                }
+                _ => log::warn!("file: ignore unknown parameter {key:?}"),
+            }
+        }
+        Ok(opts)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.path.to_string_lossy().ends_with('/')
+    }
+}
+
+/// What is stored in the checkpoint: the file being read, how far its
+/// records have been handled, and its fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Position {
+    file: PathBuf,
+    offset: u64,
+    fingerprint: String,
+}
+
+impl Position {
+    fn parse(position: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(position).ok()?;
+        Some(Position {
+            file: PathBuf::from(value.get("file")?.as_str()?),
+            offset: value.get("offset")?.as_u64()?,
+            fingerprint: value.get("fingerprint")?.as_str()?.to_string(),
+        })
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "file": self.file.to_string_lossy(),
+            "offset": self.offset,
+            "fingerprint": self.fingerprint,
+        })
+        .to_string()
+    }
+}
+
+struct OpenFile {
+    path: PathBuf,
+    file: File,
+    /// The end of the last record handed out.
+    offset: u64,
+    /// Bytes read after `offset` that do not make a whole record yet.
+    pending: Vec<u8>,
+    fingerprint: String,
+    csv_header: Option<Vec<String>>,
+}
+
+impl OpenFile {
+    fn open(path: &Path, offset: u64) -> TransformerResult<Self> {
+        let mut file = File::open(path)?;
+        let fingerprint = fingerprint(&mut file)?;
+        Ok(OpenFile {
+            path: path.to_path_buf(),
+            file,
+            offset,
+            pending: Vec::new(),
+            fingerprint,
+            csv_header: None,
+        })
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            file: self.path.clone(),
+            offset: self.offset,
+            fingerprint: self.fingerprint.clone(),
+        }
+    }
+
+    /// Reads what was appended since the last call, `false` if nothing was.
+    fn read(&mut self) -> TransformerResult<bool> {
+        let mut buf = vec![0; READ_SIZE];
+        self.file
+            .seek(SeekFrom::Start(self.offset + self.pending.len() as u64))?;
+        let n = self.file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.fingerprint.len() < FINGERPRINT_SIZE as usize * 2 {
+            // the file was shorter than a fingerprint when it was opened
+            self.fingerprint = fingerprint(&mut self.file)?;
+        }
+        Ok(true)
+    }
+
+    /// Takes the whole records out of `pending`. At the end of a file that
+    /// will not grow any more, `last` also takes a final line or row that has
+    /// no line break.
+    fn records(&mut self, format: Format, last: bool) -> TransformerResult<Vec<Vec<u8>>> {
+        if format == Format::Csv && self.csv_header.is_none() {
+            self.csv_header = self.read_csv_header()?;
+        }
+        let mut records = Vec::new();
+        let mut consumed = 0;
+        match format {
+            Format::Lines => {
+                while let Some(end) = self.pending[consumed..].iter().position(|b| *b == b'\n') {
+                    push_line(&mut records, &self.pending[consumed..consumed + end]);
+                    consumed += end + 1;
+                }
+                if last {
+                    push_line(&mut records, &self.pending[consumed..]);
+                    consumed = self.pending.len();
+                }
+            }
+            Format::Csv => {
+                while let Some((row, len)) = split_csv_row(&self.pending[consumed..], last) {
+                    // the header row itself is at offset 0
+                    if self.offset > 0 || consumed > 0 {
+                        if let Some(record) = csv_record(self.csv_header.as_deref(), row) {
+                            records.push(record);
+                        }
+                    }
+                    consumed += len;
+                }
+            }
+            Format::Json => {
+                let mut values = serde_json::Deserializer::from_slice(&self.pending).into_iter();
+                loop {
+                    match values.next() {
+                        Some(Ok(Value::Array(elements))) => {
+                            records.extend(elements.iter().map(|v| v.to_string().into_bytes()))
+                        }
+                        Some(Ok(value)) => records.push(value.to_string().into_bytes()),
+                        Some(Err(e)) if e.is_eof() => break,
+                        Some(Err(e)) => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Source,
+                                format!(
+                                    "file: invalid JSON in {} at byte {}: {e}",
+                                    self.path.display(),
+                                    self.offset as usize + values.byte_offset()
+                                ),
+                            ))
+                        }
+                        None => break,
+                    }
+                    consumed = values.byte_offset();
+                }
+                if last
+                    && self.pending[consumed..]
+                        .iter()
+                        .any(|b| !b.is_ascii_whitespace())
+                {
+                    log::warn!(
+                        "file: incomplete JSON at the end of {}",
+                        self.path.display()
+                    );
+                }
+            }
+        }
+        self.pending.drain(..consumed);
+        self.offset += consumed as u64;
+        Ok(records)
+    }
+
+    fn read_csv_header(&mut self) -> TransformerResult<Option<Vec<String>>> {
+        if self.offset == 0 {
+            return Ok(split_csv_row(&self.pending, false).map(|(row, _)| row));
+        }
+        let mut head = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        (&mut self.file)
+            .take(READ_SIZE as u64)
+            .read_to_end(&mut head)?;
+        Ok(split_csv_row(&head, false).map(|(row, _)| row))
+    }
+}
+
+/// Follows a file or a directory, and hands out its records in batches.
+pub(crate) struct FileTail {
+    opts: FileOptions,
+    current: Option<OpenFile>,
+    /// The last file of the directory that was read completely, when there is
+    /// no current one.
+    done: Option<PathBuf>,
+}
+
+impl FileTail {
+    /// Resumes from a checkpoint written by `position`, if it still matches
+    /// the files on disk.
+    pub fn open(opts: FileOptions, checkpoint: Option<String>) -> TransformerResult<Self> {
+        let mut tail = FileTail {
+            opts,
+            current: None,
+            done: None,
+        };
+        let checkpoint = checkpoint.as_deref().and_then(Position::parse);
+        tail.current = match checkpoint {
+            Some(position) => tail.resume(position)?,
+            None => tail.first_file()?,
+        };
+        Ok(tail)
+    }
+
+    pub fn position(&self) -> Option<String> {
+        self.current.as_ref().map(|file| file.position().to_json())
+    }
+
+    /// Waits until there are new records.
+    pub async fn next_batch(&mut self) -> TransformerResult<Vec<Vec<u8>>> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                if file.read()? {
+                    let records = file.records(self.opts.format, false)?;
+                    if !records.is_empty() {
+                        return Ok(records);
+                    }
+                    continue;
+                }
+            }
+            // drained the current file, see whether it was rotated or a newer
+            // file arrived
+            if let Some(next) = self.next_file()? {
+                let records = match self.current.as_mut() {
+                    Some(file) => file.records(self.opts.format, true)?,
+                    None => Vec::new(),
+                };
+                log::info!("file: read {}", next.path.display());
+                self.current = Some(next);
+                if !records.is_empty() {
+                    return Ok(records);
+                }
+                continue;
+            }
+            tokio::time::sleep(self.opts.poll_interval).await;
+        }
+    }
+
+    fn resume(&mut self, position: Position) -> TransformerResult<Option<OpenFile>> {
+        if position.file.is_file() {
+            let file = OpenFile::open(&position.file, position.offset)?;
+            if file.fingerprint.starts_with(&position.fingerprint)
+                && file.file.metadata()?.len() >= position.offset
+            {
+                log::info!(
+                    "file: resume {} at {}",
+                    position.file.display(),
+                    position.offset
+                );
+                return Ok(Some(file));
+            }
+        }
+        if !self.opts.is_dir() {
+            return self.open_path(0);
+        }
+        // the file is gone or replaced, carry on with the next one
+        let next = self.files()?.into_iter().find(|path| *path > position.file);
+        self.done = Some(position.file);
+        next.map(|path| OpenFile::open(&path, 0)).transpose()
+    }
+
+    fn first_file(&self) -> TransformerResult<Option<OpenFile>> {
+        if !self.opts.is_dir() {
+            let offset = match std::fs::metadata(&self.opts.path) {
+                Ok(metadata) if self.opts.from_end => metadata.len(),
+                _ => 0,
+            };
+            return self.open_path(offset);
+        }
+        let files = self.files()?;
+        match (self.opts.from_end, files.last()) {
+            (true, Some(path)) => {
+                let offset = std::fs::metadata(path)?.len();
+                OpenFile::open(path, offset).map(Some)
+            }
+            _ => files
+                .first()
+                .map(|path| OpenFile::open(path, 0))
+                .transpose(),
+        }
+    }
+
+    /// The file to read after the current one is drained, if it has been
+    /// rotated, truncated or followed by a newer file.
+    fn next_file(&mut self) -> TransformerResult<Option<OpenFile>> {
+        if self.opts.is_dir() {
+            let files = self.files()?;
+            let next = match &self.current {
+                Some(file) => {
+                    if let Some(next) = files.iter().find(|path| **path > file.path) {
+                        next
+                    } else {
+                        self.check_truncated()?;
+                        return Ok(None);
+                    }
+                }
+                None => match files.iter().find(|path| Some(*path) > self.done.as_ref()) {
+                    Some(first) => first,
+                    None => return Ok(None),
+                },
+            };
+            return OpenFile::open(next, 0).map(Some);
+        }
+        let file = match self.open_path(0)? {
+            Some(file) => file,
+            // rotated away and not created again yet
+            None => return Ok(None),
+        };
+        let current = match &self.current {
+            Some(current) => current,
+            None => return Ok(Some(file)),
+        };
+        // the current file is drained, so if it is still the file at the path
+        // the path cannot have more data than it
+        let rotated = (current.fingerprint.is_empty() && !file.fingerprint.is_empty())
+            || (!file.fingerprint.starts_with(&current.fingerprint)
+                && !current.fingerprint.starts_with(&file.fingerprint));
+        if rotated {
+            log::info!("file: {} was rotated", self.opts.path.display());
+            return Ok(Some(file));
+        }
+        self.check_truncated()?;
+        Ok(None)
+    }
+
+    /// Starts the current file over when it was truncated in place.
+    fn check_truncated(&mut self) -> TransformerResult<()> {
+        if let Some(file) = self.current.as_mut() {
+            let len = file.file.metadata()?.len();
+            if len < file.offset + file.pending.len() as u64 {
+                log::info!("file: {} was truncated", file.path.display());
+                file.offset = 0;
+                file.pending.clear();
+                file.csv_header = None;
+                file.fingerprint = fingerprint(&mut file.file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the followed file, or `None` while it does not exist.
+    fn open_path(&self, offset: u64) -> TransformerResult<Option<OpenFile>> {
+        if !self.opts.path.is_file() {
+            return Ok(None);
+        }
+        OpenFile::open(&self.opts.path, offset).map(Some)
+    }
+
+    /// The files of the directory to read, in name order.
+    fn files(&self) -> TransformerResult<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.opts.path)? {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .is_none_or(|name| name.to_string_lossy().starts_with('.'));
+            let matches = match &self.opts.extension {
+                Some(extension) => path.extension().is_some_and(|e| e == extension.as_str()),
+                None => true,
+            };
+            if !hidden && matches && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+fn fingerprint(file: &mut File) -> TransformerResult<String> {
+    let mut head = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(FINGERPRINT_SIZE).read_to_end(&mut head)?;
+    Ok(head.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn push_line(records: &mut Vec<Vec<u8>>, line: &[u8]) {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if !line.is_empty() {
+        records.push(line.to_vec());
+    }
+}
+
+pub(crate) fn csv_record(header: Option<&[String]>, row: Vec<String>) -> Option<Vec<u8>> {
+    if row.len() == 1 && row[0].is_empty() {
+        return None;
+    }
+    let mut record = serde_json::Map::new();
+    for (i, field) in row.into_iter().enumerate() {
+        let key = header
+            .and_then(|header| header.get(i))
+            .cloned()
+            .unwrap_or_else(|| i.to_string());
+        record.insert(key, Value::String(field));
+    }
+    Some(Value::Object(record).to_string().into_bytes())
+}
+
+/// Splits off the first CSV row, with its length in bytes, once its line
+/// break (outside of quotes) has been read, or at `last` once the input ends.
+pub(crate) fn split_csv_row(buf: &[u8], last: bool) -> Option<(Vec<String>, usize)> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut quoted = false;
+    let mut i = 0;
+    while i < buf.len() {
+        match (buf[i], quoted) {
+            (b'"', true) if buf.get(i + 1) == Some(&b'"') => {
+                field.push(b'"');
+                i += 1;
+            }
+            (b'"', true) => quoted = false,
+            (b'"', false) if field.is_empty() => quoted = true,
+            (b',', false) => {
+                fields.push(String::from_utf8_lossy(&std::mem::take(&mut field)).into_owned())
+            }
+            (b'\n', false) => {
+                if field.last() == Some(&b'\r') {
+                    field.pop();
+                }
+                fields.push(String::from_utf8_lossy(&field).into_owned());
+                return Some((fields, i + 1));
+            }
+            (b, _) => field.push(b),
+        }
+        i += 1;
+    }
+    if last && !buf.is_empty() {
+        fields.push(String::from_utf8_lossy(&field).into_owned());
+        return Some((fields, buf.len()));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// An empty scratch directory for one test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = PathBuf::from("target/test-file").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn options(path: &Path, format: &str) -> FileOptions {
+        let url = Url::parse(&format!(
+            "file://{}?format={format}&poll_ms=10",
+            path.display()
+        ));
+        FileOptions::parse(&url.unwrap()).unwrap()
+    }
+
+    fn next_batch(tail: &mut FileTail) -> Vec<String> {
+        let batch = block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), tail.next_batch()).await
+        });
+        let records = batch.expect("no records within 5s").unwrap();
+        records
+            .into_iter()
+            .map(|record| String::from_utf8(record).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let url = Url::parse("file://logs/?format=csv&extension=.csv&start=end").unwrap();
+        let opts = FileOptions::parse(&url).unwrap();
+        assert_eq!(opts.path, PathBuf::from("logs/"));
+        assert_eq!(opts.format, Format::Csv);
+        assert_eq!(opts.extension.as_deref(), Some("csv"));
+        assert!(opts.from_end && opts.is_dir());
+
+        let url = Url::parse("file:///var/log/app.log?format=xml").unwrap();
+        let e = FileOptions::parse(&url).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn splits_csv_rows() {
+        let buf = b"a,\"b,c\",\"say \"\"hi\"\"\"\r\nnext";
+        let (row, len) = split_csv_row(buf, false).unwrap();
+        assert_eq!(row, ["a", "b,c", "say \"hi\""]);
+        assert_eq!(&buf[len..], b"next");
+        assert_eq!(split_csv_row(&buf[len..], false), None);
+        assert_eq!(
+            split_csv_row(&buf[len..], true),
+            Some((vec!["next".to_string()], 4))
+        );
+        // a line break inside quotes is part of the field
+        assert_eq!(split_csv_row(b"\"1\n2", false), None);
+        assert_eq!(
+            split_csv_row(b"\"1\n2\"\n", false),
+            Some((vec!["1\n2".to_string()], 6))
+        );
+    }
+
+    #[test]
+    fn keys_csv_records_by_the_header() {
+        let header = ["id".to_string(), "name".to_string()];
+        let record = csv_record(Some(&header), vec!["1".into(), "a".into(), "x".into()]);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&record.unwrap()).unwrap(),
+            serde_json::json!({"id": "1", "name": "a", "2": "x"})
+        );
+        assert_eq!(csv_record(Some(&header), vec![String::new()]), None);
+    }
+
+    #[test]
+    fn round_trips_positions() {
+        let position = Position {
+            file: PathBuf::from("logs/1.log"),
+            offset: 42,
+            fingerprint: "6869".into(),
+        };
+        assert_eq!(Position::parse(&position.to_json()), Some(position));
+        assert_eq!(Position::parse("{\"file\":\"a\"}"), None);
+    }
+
+    #[test]
+    fn reads_csv_and_json_records() {
+        let dir = scratch("formats");
+        let csv = dir.join("orders.csv");
+        std::fs::write(&csv, "id,name\n1,a\n2,\"b\n").unwrap();
+        let mut tail = FileTail::open(options(&csv, "csv"), None).unwrap();
+        assert_eq!(next_batch(&mut tail), [r#"{"id":"1","name":"a"}"#]);
+
+        let json = dir.join("orders.json");
+        std::fs::write(&json, "[{\"id\":1},{\"id\":2}] {\"id\":3} {\"id\"").unwrap();
+        let mut tail = FileTail::open(options(&json, "json"), None).unwrap();
+        assert_eq!(
+            next_batch(&mut tail),
+            [r#"{"id":1}"#, r#"{"id":2}"#, r#"{"id":3}"#]
+        );
+    }
+
+    #[test]
+    fn resumes_from_a_checkpoint() {
+        let path = scratch("resume").join("app.log");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let mut tail = FileTail::open(options(&path, "lines"), None).unwrap();
+        assert_eq!(next_batch(&mut tail), ["a", "b"]);
+        let position = tail.position();
+
+        std::fs::write(&path, "a\nb\nc\n").unwrap();
+        let mut tail = FileTail::open(options(&path, "lines"), position.clone()).unwrap();
+        assert_eq!(next_batch(&mut tail), ["c"]);
+
+        // another file at the same path is read from its start
+        std::fs::write(&path, "x\ny\nz\n").unwrap();
+        let mut tail = FileTail::open(options(&path, "lines"), position).unwrap();
+        assert_eq!(next_batch(&mut tail), ["x", "y", "z"]);
+    }
+
+    #[test]
+    fn follows_a_rotated_file() {
+        let dir = scratch("rotate");
+        let path = dir.join("app.log");
+        std::fs::write(&path, "a\nb\nno line break").unwrap();
+        let mut tail = FileTail::open(options(&path, "lines"), None).unwrap();
+        assert_eq!(next_batch(&mut tail), ["a", "b"]);
+
+        std::fs::rename(&path, dir.join("app.log.1")).unwrap();
+        std::fs::write(&path, "c\n").unwrap();
+        // the rest of the rotated file comes first
+        assert_eq!(next_batch(&mut tail), ["no line break"]);
+        assert_eq!(next_batch(&mut tail), ["c"]);
+    }
+
+    #[test]
+    fn starts_a_truncated_file_over() {
+        let dir = scratch("truncate");
+        std::fs::write(dir.join("1.log"), "aaaa\nbbbb\n").unwrap();
+        let url = Url::parse(&format!("file://{}/?poll_ms=10", dir.display())).unwrap();
+        let mut tail = FileTail::open(FileOptions::parse(&url).unwrap(), None).unwrap();
+        assert_eq!(next_batch(&mut tail), ["aaaa", "bbbb"]);
+
+        std::fs::write(dir.join("1.log"), "c\n").unwrap();
+        assert_eq!(next_batch(&mut tail), ["c"]);
+
+        std::fs::write(dir.join("2.log"), "d\n").unwrap();
+        assert_eq!(next_batch(&mut tail), ["d"]);
+    }
+}
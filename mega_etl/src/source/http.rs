@@ -0,0 +1,604 @@
+//! The webhook server: routes each request by path and method to the
+//! transformer of its route.
+
+use super::auth::Auth;
+use super::batch::Split;
+use super::body::{BodyOptions, Rejection};
+use super::limit::{LimitOptions, Limiter};
+use crate::sink::{Sinks, SinksConn};
+use crate::tls::{Acceptor, ServerTlsOptions};
+use crate::{
+    handle_record, ErrorKind, Handled, Metadata, Summary, Transformer, TransformerError,
+    TransformerResult,
+};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, Http};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::json;
+use std::any::TypeId;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// How long a client has to finish the TLS handshake, so that connections
+/// that never do are not kept open.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type HandleFuture = Pin<Box<dyn Future<Output = TransformerResult<Handled>> + Send>>;
+type InitFuture = Pin<Box<dyn Future<Output = TransformerResult<String>> + Send>>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct HttpOptions {
+    pub host: String,
+    pub port: u16,
+    pub auth: Auth,
+    pub body: BodyOptions,
+    /// Runs the transformer per item of a request, rather than once.
+    pub split: Option<Split>,
+    pub limits: LimitOptions,
+    /// Set for `https://`.
+    pub tls: Option<ServerTlsOptions>,
+}
+
+impl HttpOptions {
+    /// Reads `http://0.0.0.0:3344`, or `https://0.0.0.0:3344?cert=..&key=..`,
+    /// with the parameters of `Auth`, `BodyOptions`, `Split`, `LimitOptions`
+    /// and `ServerTlsOptions`.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let host = url.host_str().ok_or(url::ParseError::EmptyHost)?;
+        let port = url
+            .port_or_known_default()
+            .ok_or(url::ParseError::InvalidPort)?;
+        Ok(HttpOptions {
+            host: host.to_string(),
+            port,
+            auth: Auth::parse(url)?,
+            body: BodyOptions::parse(url)?,
+            split: Split::parse(url)?,
+            limits: LimitOptions::parse(url)?,
+            tls: match url.scheme() {
+                "https" => Some(ServerTlsOptions::parse(url)?),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// A path of the webhook server, given to `Pipe::with_route` with the
+/// transformer its requests go to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    path: String,
+    method: Option<Method>,
+    sink: Option<String>,
+}
+
+impl Route {
+    /// Takes requests to exactly this path, with any method.
+    pub fn new<P: Into<String>>(path: P) -> Self {
+        Route {
+            path: path.into(),
+            method: None,
+            sink: None,
+        }
+    }
+
+    /// Only takes requests with this method. Other methods get a 405.
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Sends the outputs that name no sink to this sink of the pipe, rather
+    /// than to all of them. `init()` and `transform_save()` run on it too.
+    pub fn sink<S: Into<String>>(mut self, sink: S) -> Self {
+        self.sink = Some(sink.into());
+        self
+    }
+}
+
+/// A route with its transformer, which is a type, so it is kept as the
+/// functions instantiated for it.
+#[derive(Clone)]
+pub(crate) struct RouteHandler {
+    route: Route,
+    transformer: TypeId,
+    handle: fn(Vec<u8>, SinksConn) -> HandleFuture,
+    init: fn() -> InitFuture,
+}
+
+impl RouteHandler {
+    pub fn new<T: Transformer + 'static>(route: Route) -> Self {
+        RouteHandler {
+            route,
+            transformer: TypeId::of::<T>(),
+            handle: |content, conn| {
+                Box::pin(
+                    async move { handle_record::<T>(content, &Metadata::default(), conn).await },
+                )
+            },
+            init: || T::init(),
+        }
+    }
+}
+
+pub(crate) struct Router {
+    routes: Vec<RouteHandler>,
+    /// Takes every request when there are no routes.
+    fallback: Option<RouteHandler>,
+    auth: Auth,
+    body: BodyOptions,
+    split: Option<Split>,
+    limiter: Limiter,
+}
+
+impl Router {
+    /// Routes to `routes`, or everything to `T` if there are none.
+    pub fn new<T: Transformer + 'static>(
+        routes: &[RouteHandler],
+        sink: &Sinks,
+        opts: &HttpOptions,
+    ) -> TransformerResult<Self> {
+        for handler in routes {
+            if let Some(name) = handler.route.sink.as_deref().filter(|name| !sink.has(name)) {
+                return Err(TransformerError::new(
+                    ErrorKind::Config,
+                    format!("Unknown sink {name} for route {}", handler.route.path),
+                ));
+            }
+        }
+        Ok(Router {
+            routes: routes.to_vec(),
+            fallback: routes
+                .is_empty()
+                .then(|| RouteHandler::new::<T>(Route::new("/"))),
+            auth: opts.auth.clone(),
+            body: opts.body.clone(),
+            split: opts.split,
+            limiter: Limiter::new(opts.limits.clone()),
+        })
+    }
+
+    /// Runs `init()` of each transformer of the routes other than `T`, whose
+    /// `init()` has run already, once per sink.
+    pub async fn init<T: Transformer + 'static>(&self, sink: &Sinks) -> TransformerResult<()> {
+        let mut done = vec![(TypeId::of::<T>(), None)];
+        for handler in &self.routes {
+            let key = (handler.transformer, handler.route.sink.as_deref());
+            if done.contains(&key) {
+                continue;
+            }
+            let sql_string = (handler.init)().await?;
+            match key.1 {
+                Some(name) => sink.init_on(name, sql_string).await?,
+                None => sink.init(sql_string).await?,
+            }
+            done.push(key);
+        }
+        Ok(())
+    }
+
+    pub async fn handle(
+        &self,
+        req: Request<Body>,
+        remote: IpAddr,
+        sink: &Sinks,
+    ) -> anyhow::Result<Response<Body>> {
+        if let Err(reason) = self.auth.check_peer(remote) {
+            log::warn!("http: reject request from {remote}: {reason}");
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+        if let Err(reason) = self.auth.check_headers(req.headers()) {
+            log::warn!("http: reject request from {remote}: {reason}");
+            return Ok(unauthorized());
+        }
+        // held until the request is handled
+        let _permit = match self
+            .limiter
+            .admit(remote, self.auth.credential(req.headers()))
+        {
+            Ok(permit) => permit,
+            Err(retry_after) => return Ok(too_many_requests(remote, retry_after)),
+        };
+        let handler = match self.find(req.method(), req.uri().path()) {
+            Ok(handler) => handler,
+            Err(response) => return Ok(response),
+        };
+
+        if let Err(rejection) = self.body.check_headers(req.headers()) {
+            return Ok(reject(remote, rejection));
+        }
+
+        log::debug!("receive data");
+        let (parts, body) = req.into_parts();
+        let content = match self.body.read(&parts.headers, body).await {
+            Ok(content) => content,
+            Err(rejection) => return Ok(reject(remote, rejection)),
+        };
+        if let Err(reason) = self.auth.check_body(&parts.headers, &content) {
+            log::warn!("http: reject request from {remote}: {reason}");
+            return Ok(status(StatusCode::UNAUTHORIZED));
+        }
+
+        // a connection of the pool per request, so requests on one HTTP
+        // connection do not wait on each other
+        let conn = match sink.get_conn().await {
+            Ok(conn) => conn.routed_to(handler.route.sink.as_deref()),
+            Err(e) => {
+                log::error!("{:?}", e.to_string());
+                return Ok(status(StatusCode::SERVICE_UNAVAILABLE));
+            }
+        };
+        if let Some(split) = self.split {
+            return Ok(match split.items(&content) {
+                Ok(items) => handle_items(handler, items, conn).await,
+                Err(rejection) => reject(remote, rejection),
+            });
+        }
+        let body = match (handler.handle)(content, conn).await {
+            Ok(Handled::Saved) => "Success".to_string(),
+            Ok(Handled::Skipped) => "skip this data".to_string(),
+            Err(e) => error_message(e),
+        };
+        Ok(Response::new(Body::from(body)))
+    }
+
+    /// The handler of the route a request goes to, or else a 404 or 405.
+    fn find(&self, method: &Method, path: &str) -> Result<&RouteHandler, Response<Body>> {
+        if let Some(handler) = &self.fallback {
+            return Ok(handler);
+        }
+        let on_path: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|h| h.route.path == path)
+            .collect();
+        if on_path.is_empty() {
+            return Err(status(StatusCode::NOT_FOUND));
+        }
+        if let Some(handler) = on_path
+            .iter()
+            .find(|h| h.route.method.as_ref().is_none_or(|m| m == method))
+        {
+            return Ok(handler);
+        }
+        let methods: Vec<_> = on_path
+            .iter()
+            .filter_map(|h| h.route.method.as_ref().map(Method::as_str))
+            .collect();
+        let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+        if let Ok(allow) = HeaderValue::from_str(&methods.join(", ")) {
+            response.headers_mut().insert(ALLOW, allow);
+        }
+        Err(response)
+    }
+}
+
+/// Runs each item through the transformer, and answers with how each went,
+/// e.g. `{"processed":1,"skipped":0,"failed":1,"items":[{"index":0,
+/// "status":"processed"},{"index":1,"status":"failed","error":"..."}]}`.
+async fn handle_items(
+    handler: &RouteHandler,
+    items: Vec<Result<Vec<u8>, String>>,
+    conn: SinksConn,
+) -> Response<Body> {
+    let mut summary = Summary::default();
+    let mut results = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        let handled = match item {
+            Ok(content) => (handler.handle)(content, conn.clone()).await,
+            Err(message) => Err(TransformerError::rejected("invalid_item", message)),
+        };
+        results.push(match handled {
+            Ok(Handled::Saved) => {
+                summary.processed += 1;
+                json!({"index": index, "status": "processed"})
+            }
+            Ok(Handled::Skipped) => {
+                summary.skipped += 1;
+                json!({"index": index, "status": "skipped"})
+            }
+            Err(e) => {
+                log::error!("http: item {index}: {e} ({})", e.reason());
+                summary.failed += 1;
+                let reason = e.reason().to_string();
+                json!({
+                    "index": index,
+                    "status": "failed",
+                    "reason": reason,
+                    "error": error_message(e),
+                })
+            }
+        });
+    }
+    let body = json!({
+        "processed": summary.processed,
+        "skipped": summary.skipped,
+        "failed": summary.failed,
+        "items": results,
+    });
+    let mut response = Response::new(Body::from(body.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error_message(e: TransformerError) -> String {
+    match e {
+        TransformerError::Custom(err) => err,
+        TransformerError::Failed {
+            kind: ErrorKind::Transform,
+            message,
+            ..
+        } => message,
+        TransformerError::Unknown => "Unknown error".to_string(),
+        e => e.to_string(),
+    }
+}
+
+/// Serves HTTPS on `addr`, one task per connection.
+pub(crate) async fn serve_tls(
+    addr: SocketAddr,
+    acceptor: Acceptor,
+    router: Arc<Router>,
+    sink: Arc<Sinks>,
+) -> TransformerResult<()> {
+    let mut incoming = AddrIncoming::bind(&addr)
+        .map_err(|e| TransformerError::new(ErrorKind::Connection, format!("https: {e}")))?;
+    let acceptor = Arc::new(acceptor);
+    loop {
+        let stream = match std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await
+        {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                log::error!("https: accept: {e}");
+                continue;
+            }
+            None => return Ok(()),
+        };
+        let remote = stream.remote_addr().ip();
+        let acceptor = acceptor.clone();
+        let router = router.clone();
+        let sink = sink.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        log::warn!("https: handshake with {remote}: {e}");
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!("https: handshake with {remote} timed out");
+                        return;
+                    }
+                };
+            let service = service_fn(move |req| {
+                let sink = sink.clone();
+                let router = router.clone();
+                async move { router.handle(req, remote, &sink).await }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                log::debug!("https: connection from {remote}: {e}");
+            }
+        });
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.canonical_reason().unwrap_or_default()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Answers with the status of the rejection, and why.
+fn reject(remote: IpAddr, rejection: Rejection) -> Response<Body> {
+    log::warn!("http: reject request from {remote}: {}", rejection.reason);
+    let mut response = Response::new(Body::from(rejection.reason));
+    *response.status_mut() = rejection.status;
+    response
+}
+
+fn too_many_requests(remote: IpAddr, retry_after: Duration) -> Response<Body> {
+    log::debug!("http: limit request from {remote}");
+    let mut response = status(StatusCode::TOO_MANY_REQUESTS);
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    response
+}
+
+fn unauthorized() -> Response<Body> {
+    let mut response = status(StatusCode::UNAUTHORIZED);
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::SinkConfig;
+    use crate::{async_trait, FailurePolicy, DEFAULT_SINK};
+    use hyper::HeaderMap;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    struct Upper;
+
+    #[async_trait]
+    impl Transformer for Upper {
+        async fn transform(inbound_data: &Vec<u8>) -> TransformerResult<Vec<String>> {
+            Ok(vec![String::from_utf8_lossy(inbound_data).to_uppercase()])
+        }
+    }
+
+    struct Lower;
+
+    #[async_trait]
+    impl Transformer for Lower {
+        async fn transform(inbound_data: &Vec<u8>) -> TransformerResult<Vec<String>> {
+            Ok(vec![String::from_utf8_lossy(inbound_data).to_lowercase()])
+        }
+    }
+
+    /// File sinks `default` and `audit` under `target/test-http/<name>`.
+    async fn sinks(name: &str) -> Sinks {
+        let dir = format!("target/test-http/{name}");
+        std::fs::create_dir_all(&dir).unwrap();
+        let configs: Vec<_> = [DEFAULT_SINK, "audit"]
+            .into_iter()
+            .map(|sink| {
+                let path = format!("{dir}/{sink}");
+                let _ = std::fs::remove_file(&path);
+                SinkConfig {
+                    name: sink.into(),
+                    uri: format!("file://{path}"),
+                    policy: FailurePolicy::Fail,
+                }
+            })
+            .collect();
+        Sinks::connect(&configs).await.unwrap()
+    }
+
+    fn written(name: &str, sink: &str) -> String {
+        std::fs::read_to_string(format!("target/test-http/{name}/{sink}")).unwrap_or_default()
+    }
+
+    fn options(query: &str) -> HttpOptions {
+        HttpOptions::parse(&Url::parse(&format!("http://127.0.0.1:3344/?{query}")).unwrap())
+            .unwrap()
+    }
+
+    async fn send(
+        router: &Router,
+        sink: &Sinks,
+        request: Request<Body>,
+    ) -> (StatusCode, HeaderMap, String) {
+        let response = router
+            .handle(request, [127, 0, 0, 1].into(), sink)
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = options("token=t");
+        assert_eq!((opts.host.as_str(), opts.port), ("127.0.0.1", 3344));
+        assert!(opts.tls.is_none() && opts.split.is_none());
+        let https = |query: &str| HttpOptions::parse(&Url::parse(query).unwrap());
+        assert!(https("https://0.0.0.0?cert=c.pem").is_err());
+        let opts = https("https://0.0.0.0?cert=c.pem&key=k.pem").unwrap();
+        assert_eq!(opts.port, 443);
+        assert!(opts.tls.is_some());
+    }
+
+    #[test]
+    fn routes_requests_by_path_and_method() {
+        let routes = [
+            RouteHandler::new::<Upper>(Route::new("/orders").method(Method::POST)),
+            RouteHandler::new::<Lower>(Route::new("/orders").method(Method::PUT)),
+            RouteHandler::new::<Lower>(Route::new("/audit").sink("audit")),
+        ];
+        block_on(async {
+            let sink = sinks("routes").await;
+            let router = Router::new::<Upper>(&routes, &sink, &options("")).unwrap();
+
+            let (status, _, body) =
+                send(&router, &sink, request(Method::POST, "/orders", "a")).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "Success"));
+            send(&router, &sink, request(Method::PUT, "/orders", "B")).await;
+            send(&router, &sink, request(Method::GET, "/audit?x=1", "C")).await;
+
+            let (status, headers, _) =
+                send(&router, &sink, request(Method::GET, "/orders", "")).await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(headers[ALLOW], "POST, PUT");
+            let (status, _, _) = send(&router, &sink, request(Method::POST, "/order", "")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        });
+        assert_eq!(written("routes", DEFAULT_SINK), "A\nb\n");
+        assert_eq!(written("routes", "audit"), "A\nb\nc\n");
+    }
+
+    #[test]
+    fn sends_everything_to_the_transformer_without_routes() {
+        block_on(async {
+            let sink = sinks("fallback").await;
+            let router = Router::new::<Upper>(&[], &sink, &options("")).unwrap();
+            for path in ["/", "/anything"] {
+                let (status, _, _) = send(&router, &sink, request(Method::PUT, path, "x")).await;
+                assert_eq!(status, StatusCode::OK);
+            }
+        });
+        assert_eq!(written("fallback", DEFAULT_SINK), "X\nX\n");
+    }
+
+    #[test]
+    fn rejects_routes_to_unknown_sinks() {
+        let routes = [RouteHandler::new::<Upper>(Route::new("/").sink("missing"))];
+        let e = block_on(async {
+            let sink = sinks("unknown").await;
+            Router::new::<Upper>(&routes, &sink, &options(""))
+                .err()
+                .unwrap()
+        });
+        assert!(
+            e.to_string().contains("Unknown sink missing for route /"),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn checks_requests_before_routing_them() {
+        block_on(async {
+            let sink = sinks("auth").await;
+            let opts = options("token=secret&allow_ips=127.0.0.0/8");
+            let router = Router::new::<Upper>(&[], &sink, &opts).unwrap();
+            let (status, headers, _) = send(&router, &sink, request(Method::POST, "/", "x")).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(headers[WWW_AUTHENTICATE], "Bearer");
+
+            let mut authorized = request(Method::POST, "/", "y");
+            authorized
+                .headers_mut()
+                .insert("authorization", HeaderValue::from_static("Bearer secret"));
+            let (status, _, _) = send(&router, &sink, authorized).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let response = router
+                .handle(request(Method::POST, "/", "z"), [10, 0, 0, 1].into(), &sink)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        });
+        assert_eq!(written("auth", DEFAULT_SINK), "Y\n");
+    }
+}
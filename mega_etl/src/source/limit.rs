@@ -0,0 +1,381 @@
+//! Limits how fast a source takes records in, and how many of them it
+//! handles at once.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+/// How many clients of the webhook server have a bucket of their own. Past
+/// it, the idle ones are dropped, or else the one used the longest ago.
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    /// Reads `rate=100/s`, also per minute as `/m` or per hour as `/h`, or a
+    /// bare number per second, and `burst=..`, which defaults to a second of
+    /// the rate.
+    pub fn parse(scheme: &str, url: &Url) -> TransformerResult<Option<Self>> {
+        let mut rate = None;
+        let mut burst = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "rate" => {
+                    let invalid = || {
+                        TransformerError::new(
+                            ErrorKind::Config,
+                            format!("{scheme}: invalid rate {value:?}"),
+                        )
+                    };
+                    let (count, period) = match value.split_once('/') {
+                        Some((count, "s")) => (count, 1.0),
+                        Some((count, "m")) => (count, 60.0),
+                        Some((count, "h")) => (count, 3600.0),
+                        Some(_) => return Err(invalid()),
+                        None => (value.as_ref(), 1.0),
+                    };
+                    let count: f64 = count.trim().parse().map_err(|_| invalid())?;
+                    if !count.is_finite() || count <= 0.0 {
+                        return Err(invalid());
+                    }
+                    rate = Some(count / period);
+                }
+                "burst" => match value.parse::<u32>() {
+                    Ok(count) if count > 0 => burst = Some(f64::from(count)),
+                    _ => {
+                        return Err(TransformerError::new(
+                            ErrorKind::Config,
+                            format!("{scheme}: invalid burst {value:?}"),
+                        ))
+                    }
+                },
+                _ => {}
+            }
+        }
+        match (rate, burst) {
+            (Some(per_second), burst) => Ok(Some(RateLimit {
+                per_second,
+                burst: burst.unwrap_or(per_second.max(1.0)),
+            })),
+            (None, None) => Ok(None),
+            (None, Some(_)) => Err(TransformerError::new(
+                ErrorKind::Config,
+                format!("{scheme}: burst needs a rate"),
+            )),
+        }
+    }
+}
+
+/// Lets records through at a rate, and a burst of them after a pause.
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or tells how long until there is one.
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    /// Waits until there is a token, and takes it.
+    pub async fn take(&mut self) {
+        while let Err(wait) = self.try_take() {
+            log::debug!("rate limited for {wait:?}");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+    }
+
+    /// Whether the bucket has filled up since it was last used, which leaves
+    /// `updated` at that use.
+    fn is_full(&self) -> bool {
+        let elapsed = self.updated.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.limit.per_second >= self.limit.burst
+    }
+}
+
+/// Whom a rate limit of the webhook server is counted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RateBy {
+    /// All requests together.
+    Source,
+    Ip,
+    /// The API key or bearer token of the request, or else its address.
+    ApiKey,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LimitOptions {
+    /// Requests handled at once, beyond which they are turned away.
+    pub max_in_flight: Option<usize>,
+    pub rate: Option<RateLimit>,
+    pub rate_by: RateBy,
+}
+
+impl Default for LimitOptions {
+    fn default() -> Self {
+        LimitOptions {
+            max_in_flight: None,
+            rate: None,
+            rate_by: RateBy::Source,
+        }
+    }
+}
+
+impl LimitOptions {
+    /// Reads `max_in_flight=..`, `rate_by=source|ip|api_key` and the
+    /// parameters of `RateLimit`, ignoring other parameters.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let mut opts = LimitOptions {
+            rate: RateLimit::parse("http", url)?,
+            ..Default::default()
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "max_in_flight" => match value.parse::<usize>() {
+                    Ok(max) if max > 0 => opts.max_in_flight = Some(max),
+                    _ => {
+                        return Err(TransformerError::new(
+                            ErrorKind::Config,
+                            format!("http: invalid max_in_flight {value:?}"),
+                        ))
+                    }
+                },
+                "rate_by" => {
+                    opts.rate_by = match value.as_ref() {
+                        "source" => RateBy::Source,
+                        "ip" => RateBy::Ip,
+                        "api_key" => RateBy::ApiKey,
+                        _ => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Config,
+                                format!(
+                                    "http: invalid rate_by {value:?}, use source, ip or api_key"
+                                ),
+                            ))
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Admits the requests of the webhook server within its limits.
+pub(crate) struct Limiter {
+    in_flight: Option<Arc<Semaphore>>,
+    rate: Option<RateLimit>,
+    rate_by: RateBy,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(opts: LimitOptions) -> Self {
+        Limiter {
+            in_flight: opts.max_in_flight.map(|max| Arc::new(Semaphore::new(max))),
+            rate: opts.rate,
+            rate_by: opts.rate_by,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lets a request in, which counts as in flight until the permit drops,
+    /// or tells the client when to try again.
+    pub fn admit(
+        &self,
+        remote: IpAddr,
+        credential: Option<&str>,
+    ) -> Result<Option<OwnedSemaphorePermit>, Duration> {
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(
+                in_flight
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| Duration::from_secs(1))?,
+            ),
+            None => None,
+        };
+        if let Some(limit) = self.rate {
+            let client = match (self.rate_by, credential) {
+                (RateBy::Source, _) => String::new(),
+                (RateBy::ApiKey, Some(credential)) => format!("key:{credential}"),
+                (RateBy::Ip | RateBy::ApiKey, _) => remote.to_string(),
+            };
+            let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+            if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+                buckets.retain(|_, bucket| !bucket.is_full());
+                // clients that keep all buckets busy do not grow the map
+                let oldest = (buckets.len() >= MAX_CLIENTS)
+                    .then(|| buckets.iter().min_by_key(|(_, bucket)| bucket.updated))
+                    .flatten()
+                    .map(|(client, _)| client.clone());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+            buckets
+                .entry(client)
+                .or_insert_with(|| TokenBucket::new(limit))
+                .try_take()?;
+        }
+        Ok(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(query: &str) -> Url {
+        Url::parse(&format!("http://0.0.0.0:3344/?{query}")).unwrap()
+    }
+
+    fn limits(query: &str) -> Limiter {
+        Limiter::new(LimitOptions::parse(&url(query)).unwrap())
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn parses_rates() {
+        let rate = |query| RateLimit::parse("http", &url(query));
+        assert_eq!(rate("").unwrap(), None);
+        assert_eq!(
+            rate("rate=120/m").unwrap(),
+            Some(RateLimit {
+                per_second: 2.0,
+                burst: 2.0
+            })
+        );
+        assert_eq!(
+            rate("rate=36/h&burst=5").unwrap(),
+            Some(RateLimit {
+                per_second: 0.01,
+                burst: 5.0
+            })
+        );
+        // a burst of at least one
+        assert_eq!(rate("rate=0.5").unwrap().unwrap().burst, 1.0);
+        for query in [
+            "rate=0",
+            "rate=-1/s",
+            "rate=1/d",
+            "rate=inf",
+            "burst=2",
+            "rate=1&burst=0",
+        ] {
+            assert!(rate(query).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = LimitOptions::parse(&url("max_in_flight=8&rate_by=api_key&rate=1")).unwrap();
+        assert_eq!(
+            (opts.max_in_flight, opts.rate_by),
+            (Some(8), RateBy::ApiKey)
+        );
+        assert!(opts.rate.is_some());
+        for query in ["max_in_flight=0", "rate_by=user"] {
+            assert!(LimitOptions::parse(&url(query)).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn refills_buckets_at_the_rate() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 10.0,
+            burst: 2.0,
+        });
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        let wait = bucket.try_take().unwrap_err();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        std::thread::sleep(wait);
+        assert!(bucket.try_take().is_ok());
+        assert!(!bucket.is_full());
+    }
+
+    #[test]
+    fn caps_requests_in_flight() {
+        let limiter = limits("max_in_flight=2");
+        let first = limiter.admit(ip(1), None).unwrap();
+        let _second = limiter.admit(ip(2), None).unwrap();
+        assert_eq!(
+            limiter.admit(ip(3), None).unwrap_err(),
+            Duration::from_secs(1)
+        );
+        drop(first);
+        assert!(limiter.admit(ip(3), None).is_ok());
+    }
+
+    #[test]
+    fn counts_rates_by_source_address_or_credential() {
+        let limiter = limits("rate=1/h");
+        assert!(limiter.admit(ip(1), None).is_ok());
+        assert!(limiter.admit(ip(2), Some("a")).is_err());
+
+        let limiter = limits("rate=1/h&rate_by=ip");
+        assert!(limiter.admit(ip(1), None).is_ok());
+        assert!(limiter.admit(ip(2), None).is_ok());
+        assert!(limiter.admit(ip(1), Some("a")).is_err());
+
+        let limiter = limits("rate=1/h&rate_by=api_key");
+        assert!(limiter.admit(ip(1), Some("a")).is_ok());
+        assert!(limiter.admit(ip(1), Some("b")).is_ok());
+        assert!(limiter.admit(ip(2), Some("a")).is_err());
+        // without one, by address
+        assert!(limiter.admit(ip(1), None).is_ok());
+        assert!(limiter.admit(ip(1), None).is_err());
+    }
+
+    #[test]
+    fn forgets_the_client_seen_the_longest_ago() {
+        let limiter = limits("rate=1/h&rate_by=api_key");
+        for client in 0..MAX_CLIENTS {
+            assert!(limiter.admit(ip(1), Some(&client.to_string())).is_ok());
+        }
+        // every bucket is busy, so the oldest one goes
+        assert!(limiter.admit(ip(1), Some("new")).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_CLIENTS);
+        assert!(!buckets.contains_key("key:0"));
+        assert!(buckets.contains_key("key:1") && buckets.contains_key("key:new"));
+    }
+}
@@ -0,0 +1,22 @@
+mod change;
+
+pub(crate) mod amqp;
+pub(crate) mod auth;
+pub(crate) mod batch;
+pub(crate) mod body;
+pub(crate) mod file;
+pub(crate) mod http;
+pub(crate) mod http_poll;
+pub(crate) mod kafka;
+pub(crate) mod limit;
+pub(crate) mod mqtt;
+pub(crate) mod mysql_binlog;
+pub(crate) mod mysql_poll;
+pub(crate) mod nats;
+pub(crate) mod postgres_cdc;
+pub(crate) mod timer;
+pub(crate) mod websocket;
+
+pub use change::{ChangeEvent, ChangeOp};
+pub use http::Route;
+pub use timer::Tick;
@@ -0,0 +1,367 @@
+//! Fires on a fixed interval or a cron schedule, for pipes that pull their
+//! data rather than wait for it.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, DurationRound, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+
+/// How far ahead to look for the next time a cron schedule matches, so that
+/// e.g. `0 0 30 2 *` is an error rather than an endless search.
+const CRON_HORIZON_DAYS: i64 = 366 * 5;
+
+/// The record a timer source hands to the transformer, as JSON, each time it
+/// fires. `serde_json::from_slice::<Tick>(inbound_data)` reads it back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tick {
+    /// When the tick was due, which is a little earlier than now.
+    pub scheduled_at: DateTime<Utc>,
+    /// The `scheduled_at` of the last tick the transformer handled without
+    /// an error, kept across restarts when the default sink is a database.
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Schedule {
+    Interval(Duration),
+    Cron(Cron),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TimerOptions {
+    pub schedule: Schedule,
+    /// The schedule as written, which names the checkpoint of the last
+    /// successful tick.
+    pub spec: String,
+}
+
+impl TimerOptions {
+    /// Reads `timer://?interval=30s` or `timer://?cron=*/5+*+*+*+*`, where the
+    /// cron expression has the five usual fields and is in UTC.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let mut opts = None;
+        for (key, value) in url.query_pairs() {
+            let schedule = match key.as_ref() {
+                "interval" => Schedule::Interval(parse_interval("timer", &value)?),
+                "cron" => Schedule::Cron(Cron::parse(&value)?),
+                _ => continue,
+            };
+            if opts.is_some() {
+                return Err(TransformerError::new(
+                    ErrorKind::Config,
+                    "timer: give only one of interval and cron",
+                ));
+            }
+            opts = Some(TimerOptions {
+                schedule,
+                spec: format!("{key}={value}"),
+            });
+        }
+        opts.ok_or_else(|| {
+            TransformerError::new(ErrorKind::Config, "timer: missing interval or cron")
+        })
+    }
+}
+
+impl Schedule {
+    /// The first time the schedule fires strictly after `after`. An interval
+    /// fires `after` plus the interval, so ticks do not drift while the
+    /// transformer keeps up.
+    pub fn next_after(&self, after: DateTime<Utc>) -> TransformerResult<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => Ok(after + chrono_interval(*interval)?),
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+
+    /// The tick to run after the one due at `scheduled_at`, once the
+    /// transformer is done with it at `now`. It stays on the schedule rather
+    /// than start over from `now`, and only the ticks that were missed, as a
+    /// later one is already due, are skipped.
+    pub fn next_due(
+        &self,
+        scheduled_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> TransformerResult<DateTime<Utc>> {
+        let mut next = self.next_after(scheduled_at)?;
+        match self {
+            Schedule::Interval(interval) => {
+                let step = chrono_interval(*interval)?.num_milliseconds();
+                if now > next {
+                    let missed = (now - next).num_milliseconds() / step;
+                    next += ChronoDuration::milliseconds(missed * step);
+                }
+            }
+            Schedule::Cron(cron) => loop {
+                let later = cron.next_after(next)?;
+                if later > now {
+                    break;
+                }
+                next = later;
+            },
+        }
+        Ok(next)
+    }
+}
+
+fn chrono_interval(interval: Duration) -> TransformerResult<ChronoDuration> {
+    ChronoDuration::from_std(interval)
+        .map_err(|e| TransformerError::new(ErrorKind::Config, format!("timer: {e}")).with_source(e))
+}
+
+/// Reads a duration like `500ms`, `30s`, `5m`, `1h` or `1d`, with `scheme`
+/// naming the URI in the error message.
+pub(crate) fn parse_interval(scheme: &str, value: &str) -> TransformerResult<Duration> {
+    let invalid = || {
+        TransformerError::new(
+            ErrorKind::Config,
+            format!("{scheme}: invalid interval {value:?}"),
+        )
+    };
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let count: u64 = value[..split].parse().map_err(|_| invalid())?;
+    let seconds = |unit: u64| count.checked_mul(unit).map(Duration::from_secs);
+    let interval = match &value[split..] {
+        "ms" => Some(Duration::from_millis(count)),
+        "s" => Some(Duration::from_secs(count)),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        "d" => seconds(86400),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+    if interval.is_zero() {
+        return Err(invalid());
+    }
+    Ok(interval)
+}
+
+/// A cron expression: minute, hour, day of month, month and day of week,
+/// each `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`, or a list of
+/// those. Day of week is 0-7, where both 0 and 7 are Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month and the day of week were both restricted, in
+    /// which case a day matching either one matches, as in crontab.
+    either_day: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> TransformerResult<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(TransformerError::new(
+                ErrorKind::Config,
+                format!("timer: cron {expr:?} must have 5 fields"),
+            ));
+        }
+        let mut weekdays = cron_field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: cron_field(fields[0], 0, 59)?,
+            hours: cron_field(fields[1], 0, 23)?,
+            days: cron_field(fields[2], 1, 31)?,
+            months: cron_field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, time: &DateTime<Utc>) -> bool {
+        if self.months & (1 << time.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>) -> TransformerResult<DateTime<Utc>> {
+        let minute = ChronoDuration::minutes(1);
+        let mut time = after.duration_trunc(minute).map_err(|e| {
+            TransformerError::new(ErrorKind::Config, format!("timer: {e}")).with_source(e)
+        })? + minute;
+        let horizon = time + ChronoDuration::days(CRON_HORIZON_DAYS);
+        while time < horizon {
+            if !self.matches_day(&time) {
+                // on to midnight of the next day
+                time = time + ChronoDuration::days(1)
+                    - ChronoDuration::minutes((time.hour() * 60 + time.minute()).into());
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time =
+                    time + ChronoDuration::hours(1) - ChronoDuration::minutes(time.minute().into());
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += minute;
+            } else {
+                return Ok(time);
+            }
+        }
+        Err(TransformerError::new(
+            ErrorKind::Config,
+            "timer: the cron expression never fires",
+        ))
+    }
+}
+
+/// Reads one cron field into a bit set of the values it allows.
+fn cron_field(field: &str, min: u32, max: u32) -> TransformerResult<u64> {
+    let invalid = || {
+        TransformerError::new(
+            ErrorKind::Config,
+            format!("timer: invalid cron field {field:?}"),
+        )
+    };
+    let value = |s: &str| -> TransformerResult<u32> {
+        s.parse()
+            .ok()
+            .filter(|v| (min..=max).contains(v))
+            .ok_or_else(invalid)
+    };
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else if part.contains('/') {
+            // `a/n` runs from a to the end of the field
+            (value(range)?, max)
+        } else {
+            let v = value(range)?;
+            (v, v)
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn cron(expr: &str) -> Schedule {
+        Schedule::Cron(Cron::parse(expr).unwrap())
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(
+            parse_interval("timer", "500ms").unwrap(),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            parse_interval("timer", "5m").unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            parse_interval("timer", "2d").unwrap(),
+            Duration::from_secs(172800)
+        );
+        for value in ["0s", "5", "s", "5w", "-1s", "213503982334602d"] {
+            let e = parse_interval("timer", value).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Config, "{value}");
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let url = Url::parse("timer://?cron=*/5+*+*+*+*").unwrap();
+        let opts = TimerOptions::parse(&url).unwrap();
+        assert_eq!(opts.spec, "cron=*/5 * * * *");
+        assert!(matches!(opts.schedule, Schedule::Cron(_)));
+        for uri in ["timer://", "timer://?interval=1s&cron=*+*+*+*+*"] {
+            let e = TimerOptions::parse(&Url::parse(uri).unwrap()).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Config, "{uri}");
+        }
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        assert_eq!(
+            cron_field("*/15", 0, 59).unwrap(),
+            1 | 1 << 15 | 1 << 30 | 1 << 45
+        );
+        assert_eq!(cron_field("1-3,5", 0, 59).unwrap(), 0b101110);
+        assert_eq!(cron_field("50/5", 0, 59).unwrap(), 1 << 50 | 1 << 55);
+        for field in ["60", "5-1", "*/0", "a"] {
+            assert!(cron_field(field, 0, 59).is_err(), "{field}");
+        }
+        assert!(Cron::parse("* * * *").is_err());
+        // Sunday is both 0 and 7
+        let sunday = Schedule::Cron(Cron::parse("0 0 * * 7").unwrap());
+        assert_eq!(
+            sunday.next_after(time("2024-01-01T00:00:00Z")).unwrap(),
+            time("2024-01-07T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn finds_the_next_cron_time() {
+        let after = time("2024-02-28T23:59:30Z");
+        let next = |expr: &str| cron(expr).next_after(after).unwrap();
+        assert_eq!(next("* * * * *"), time("2024-02-29T00:00:00Z"));
+        assert_eq!(next("30 9 * * 1-5"), time("2024-02-29T09:30:00Z"));
+        assert_eq!(next("0 0 1 * *"), time("2024-03-01T00:00:00Z"));
+        // a day matches when either the day of month or of week does
+        assert_eq!(next("0 12 15 * 6"), time("2024-03-02T12:00:00Z"));
+        assert_eq!(next("0 0 29 2 *"), time("2024-02-29T00:00:00Z"));
+        let e = cron("0 0 30 2 *").next_after(after).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn keeps_ticks_on_the_schedule() {
+        let interval = Schedule::Interval(Duration::from_secs(60));
+        let scheduled_at = time("2024-01-01T00:00:00Z");
+        // done in time, the next tick is one interval after the last one
+        assert_eq!(
+            interval
+                .next_due(scheduled_at, time("2024-01-01T00:00:20Z"))
+                .unwrap(),
+            time("2024-01-01T00:01:00Z")
+        );
+        // late, the ticks before the last one due are skipped
+        assert_eq!(
+            interval
+                .next_due(scheduled_at, time("2024-01-01T00:03:20Z"))
+                .unwrap(),
+            time("2024-01-01T00:03:00Z")
+        );
+        assert_eq!(
+            cron("*/5 * * * *")
+                .next_due(scheduled_at, time("2024-01-01T00:12:00Z"))
+                .unwrap(),
+            time("2024-01-01T00:10:00Z")
+        );
+    }
+}
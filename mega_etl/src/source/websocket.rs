@@ -0,0 +1,562 @@
+//! A WebSocket client (RFC 6455) for streaming feeds, which pings an idle
+//! server and reconnects with a backoff when the connection drops.
+
+use super::timer::parse_interval;
+use crate::uri::parse_param;
+use crate::{tls, ErrorKind, TransformerError, TransformerResult};
+use sha1::{Digest, Sha1};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::{form_urlencoded, Url};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, Clone)]
+pub(crate) struct WsOptions {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// The path and query of the request.
+    pub resource: String,
+    pub headers: Vec<(String, String)>,
+    /// Sent as text messages after every connect.
+    pub subscribe: Vec<String>,
+    pub ping_interval: Duration,
+    pub max_backoff: Duration,
+    /// The largest message, in bytes, over all of its frames.
+    pub max_message_size: usize,
+}
+
+impl WsOptions {
+    /// Reads `ws://host:port/path` or `wss://host/path`, with `subscribe=..`
+    /// once per message to send, `ping_interval=30s`, `max_backoff=60s`,
+    /// `max_message_size` in bytes and `header.<Name>=..`. Every other query
+    /// parameter is sent to the server as it is.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let host = url.host_str().ok_or(url::ParseError::EmptyHost)?;
+        let port = url
+            .port_or_known_default()
+            .ok_or(url::ParseError::InvalidPort)?;
+        let mut opts = WsOptions {
+            tls: url.scheme() == "wss",
+            host: host.to_string(),
+            port,
+            resource: String::new(),
+            headers: Vec::new(),
+            subscribe: Vec::new(),
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        };
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "subscribe" => opts.subscribe.push(value.into_owned()),
+                "ping_interval" => opts.ping_interval = parse_interval("ws", &value)?,
+                "max_backoff" => opts.max_backoff = parse_interval("ws", &value)?.max(MIN_BACKOFF),
+                "max_message_size" => opts.max_message_size = parse_param("ws", &key, &value)?,
+                _ => match key.strip_prefix("header.") {
+                    Some(name) => opts.headers.push((name.to_string(), value.into_owned())),
+                    None => {
+                        query.append_pair(&key, &value);
+                    }
+                },
+            }
+        }
+        let query = query.finish();
+        opts.resource = if query.is_empty() {
+            url.path().to_string()
+        } else {
+            format!("{}?{query}", url.path())
+        };
+        Ok(opts)
+    }
+}
+
+/// The stream under a connection, with or without TLS.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+struct WsConn {
+    io: Box<dyn Io>,
+    /// What was read from the stream and is not a whole frame yet.
+    buf: Vec<u8>,
+    max_message_size: usize,
+}
+
+impl WsConn {
+    async fn connect(opts: &WsOptions) -> TransformerResult<Self> {
+        let tcp = TcpStream::connect((opts.host.as_str(), opts.port)).await?;
+        let io: Box<dyn Io> = if opts.tls {
+            Box::new(tls::connect(tls::client_config(), &opts.host, tcp).await?)
+        } else {
+            Box::new(tcp)
+        };
+        let mut conn = WsConn {
+            io,
+            buf: Vec::new(),
+            max_message_size: opts.max_message_size,
+        };
+
+        let key = base64::encode(random::<16>()?);
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n",
+            opts.resource, opts.host, opts.port
+        );
+        for (name, value) in &opts.headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        conn.io.write_all(request.as_bytes()).await?;
+        conn.io.flush().await?;
+
+        let end = loop {
+            if let Some(end) = conn.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            if conn.buf.len() > MAX_HANDSHAKE_SIZE {
+                return Err(failed(ErrorKind::Connection, "handshake too large"));
+            }
+            if conn.io.read_buf(&mut conn.buf).await? == 0 {
+                return Err(failed(
+                    ErrorKind::Connection,
+                    "connection closed during the handshake",
+                ));
+            }
+        };
+        let response = String::from_utf8_lossy(&conn.buf[..end]).into_owned();
+        // what follows the response is the first frames
+        conn.buf.drain(..end + 4);
+        let mut lines = response.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(failed(
+                ErrorKind::Connection,
+                &format!("the server refused the upgrade: {status}"),
+            ));
+        }
+        let expected = base64::encode(Sha1::digest(format!("{key}{GUID}").as_bytes()));
+        let accept = lines.find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("sec-websocket-accept")
+                .then(|| value.trim())
+        });
+        if accept != Some(expected.as_str()) {
+            return Err(failed(
+                ErrorKind::Connection,
+                "invalid Sec-WebSocket-Accept",
+            ));
+        }
+        for message in &opts.subscribe {
+            conn.send(OP_TEXT, message.as_bytes()).await?;
+        }
+        Ok(conn)
+    }
+
+    /// Waits for the next text or binary message, answering pings, and
+    /// pinging the server when it has been quiet for `ping_interval`.
+    async fn next_message(&mut self, ping_interval: Duration) -> TransformerResult<Vec<u8>> {
+        let mut message: Option<Vec<u8>> = None;
+        let mut awaiting_pong = false;
+        loop {
+            // `read_frame` keeps partial frames in `buf`, so a timeout loses
+            // nothing
+            let frame = match tokio::time::timeout(ping_interval, self.read_frame()).await {
+                Ok(frame) => frame?,
+                Err(_) if awaiting_pong => {
+                    return Err(failed(ErrorKind::Connection, "no pong from the server"))
+                }
+                Err(_) => {
+                    self.send(OP_PING, b"").await?;
+                    awaiting_pong = true;
+                    continue;
+                }
+            };
+            awaiting_pong = false;
+            match frame.opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    let mut payload = match (frame.opcode, message.take()) {
+                        (OP_CONTINUATION, Some(partial)) => partial,
+                        (OP_CONTINUATION, None) => {
+                            return Err(failed(ErrorKind::Source, "unexpected continuation frame"))
+                        }
+                        (_, Some(_)) => {
+                            return Err(failed(
+                                ErrorKind::Source,
+                                "a message started before the last one ended",
+                            ))
+                        }
+                        (_, None) => Vec::new(),
+                    };
+                    // the first frame counts as much as the ones that follow
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(failed(ErrorKind::Source, "message too large"));
+                    }
+                    if payload.is_empty() {
+                        payload = frame.payload;
+                    } else {
+                        payload.extend_from_slice(&frame.payload);
+                    }
+                    if frame.fin {
+                        return Ok(payload);
+                    }
+                    message = Some(payload);
+                }
+                OP_PING => self.send(OP_PONG, &frame.payload).await?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let code = frame
+                        .payload
+                        .get(..2)
+                        .map(|code| u16::from_be_bytes([code[0], code[1]]));
+                    let _ = self
+                        .send(OP_CLOSE, frame.payload.get(..2).unwrap_or_default())
+                        .await;
+                    return Err(failed(
+                        ErrorKind::Connection,
+                        &format!("closed by the server with code {code:?}"),
+                    ));
+                }
+                opcode => {
+                    return Err(failed(
+                        ErrorKind::Source,
+                        &format!("unknown opcode {opcode}"),
+                    ))
+                }
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> TransformerResult<Frame> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(frame);
+            }
+            if self.io.read_buf(&mut self.buf).await? == 0 {
+                return Err(failed(
+                    ErrorKind::Connection,
+                    "connection closed by the server",
+                ));
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> TransformerResult<Option<Frame>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (len, mut pos) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        // a frame is never larger than the message, so wait for no more
+        if len > self.max_message_size as u64 {
+            return Err(failed(ErrorKind::Source, "message too large"));
+        }
+        let len = len as usize;
+        // servers do not mask their frames, but unmask them if they do
+        let mut mask = None;
+        if buf[1] & 0x80 != 0 {
+            if buf.len() < pos + 4 {
+                return Ok(None);
+            }
+            mask = Some([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            pos += 4;
+        }
+        if buf.len() < pos + len {
+            return Ok(None);
+        }
+        let mut payload = buf[pos..pos + len].to_vec();
+        if let Some(mask) = mask {
+            payload
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+        let frame = Frame {
+            fin: buf[0] & 0x80 != 0,
+            opcode: buf[0] & 0x0f,
+            payload,
+        };
+        self.buf.drain(..pos + len);
+        Ok(Some(frame))
+    }
+
+    /// Sends one masked frame, as clients must.
+    async fn send(&mut self, opcode: u8, payload: &[u8]) -> TransformerResult<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = random::<4>()?;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.io.write_all(&frame).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+}
+
+pub(crate) struct WsClient {
+    opts: WsOptions,
+    conn: Option<WsConn>,
+    backoff: Duration,
+}
+
+impl WsClient {
+    pub fn new(opts: WsOptions) -> Self {
+        WsClient {
+            opts,
+            conn: None,
+            backoff: MIN_BACKOFF,
+        }
+    }
+
+    /// Waits for the next text or binary message. A lost connection is
+    /// opened again, after a backoff that doubles up to `max_backoff` until
+    /// a message arrives.
+    pub async fn next(&mut self) -> Vec<u8> {
+        loop {
+            let result = match self.conn.as_mut() {
+                Some(conn) => conn.next_message(self.opts.ping_interval).await,
+                None => match WsConn::connect(&self.opts).await {
+                    Ok(conn) => {
+                        log::info!("ws: connected to {}", self.opts.host);
+                        self.conn = Some(conn);
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(message) => {
+                    self.backoff = MIN_BACKOFF;
+                    return message;
+                }
+                Err(e) => {
+                    log::warn!("{e}, reconnecting in {:?}", self.backoff);
+                    self.conn = None;
+                    tokio::time::sleep(self.backoff).await;
+                    self.backoff = (self.backoff * 2).min(self.opts.max_backoff);
+                }
+            }
+        }
+    }
+}
+
+fn random<const N: usize>() -> TransformerResult<[u8; N]> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| failed(ErrorKind::Connection, &e.to_string()))?;
+    Ok(bytes)
+}
+
+fn failed(kind: ErrorKind, message: &str) -> TransformerError {
+    TransformerError::new(kind, format!("ws: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// An unmasked frame, as servers send them.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    async fn read_client_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_ne!(head[1] & 0x80, 0, "client frames are masked");
+        let mut mask = [0; 4];
+        stream.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; (head[1] & 0x7f) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+        (head[0] & 0x0f, payload)
+    }
+
+    /// A server that accepts the upgrade, sends `frames`, and returns the
+    /// frames the client sent until it closed the connection.
+    async fn server(frames: Vec<Vec<u8>>, replies: usize) -> (u16, JoinHandle<Vec<(u8, Vec<u8>)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            let key = request
+                .lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let accept = base64::encode(Sha1::digest(format!("{key}{GUID}").as_bytes()));
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+            );
+            client.write_all(response.as_bytes()).await.unwrap();
+            client.write_all(&frames.concat()).await.unwrap();
+            let mut received = Vec::new();
+            for _ in 0..replies {
+                received.push(read_client_frame(&mut client).await);
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    fn options(port: u16, query: &str) -> WsOptions {
+        WsOptions::parse(&Url::parse(&format!("ws://127.0.0.1:{port}/feed?{query}")).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_options() {
+        let url = Url::parse(
+            "wss://feed.example.com/v1?subscribe=a&subscribe=b&header.X-Key=k&max_message_size=1024&since=1",
+        )
+        .unwrap();
+        let opts = WsOptions::parse(&url).unwrap();
+        assert!(opts.tls);
+        assert_eq!(opts.port, 443);
+        assert_eq!(opts.resource, "/v1?since=1");
+        assert_eq!(opts.subscribe, ["a", "b"]);
+        assert_eq!(opts.headers, [("X-Key".to_string(), "k".to_string())]);
+        assert_eq!(opts.max_message_size, 1024);
+    }
+
+    #[test]
+    fn parses_frames() {
+        let (io, _peer) = tokio::io::duplex(64);
+        let mut conn = WsConn {
+            io: Box::new(io),
+            buf: Vec::new(),
+            max_message_size: 300,
+        };
+        // masked, which servers do not do but may
+        conn.buf = vec![0x81, 0x83, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2, b'c' ^ 3];
+        let parsed = conn.parse_frame().unwrap().unwrap();
+        assert!(parsed.fin);
+        assert_eq!(
+            (parsed.opcode, parsed.payload.as_slice()),
+            (OP_TEXT, &b"abc"[..])
+        );
+        assert!(conn.buf.is_empty());
+
+        let long = frame(false, OP_BINARY, &[7; 200]);
+        conn.buf = long[..100].to_vec();
+        assert!(conn.parse_frame().unwrap().is_none());
+        conn.buf.extend_from_slice(&long[100..]);
+        let parsed = conn.parse_frame().unwrap().unwrap();
+        assert!(!parsed.fin);
+        assert_eq!(parsed.payload.len(), 200);
+
+        conn.buf = vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0];
+        assert!(conn.parse_frame().is_err());
+    }
+
+    #[test]
+    fn joins_fragments_and_answers_pings() {
+        let (message, received) = block_on(async {
+            let frames = vec![
+                frame(true, OP_PING, b"hi"),
+                frame(false, OP_TEXT, b"hel"),
+                frame(true, OP_CONTINUATION, b"lo"),
+            ];
+            let (port, server) = server(frames, 2).await;
+            let mut conn = WsConn::connect(&options(port, "subscribe=orders"))
+                .await
+                .unwrap();
+            let message = conn.next_message(Duration::from_secs(5)).await.unwrap();
+            (message, server.await.unwrap())
+        });
+        assert_eq!(message, b"hello");
+        assert_eq!(
+            received,
+            [(OP_TEXT, b"orders".to_vec()), (OP_PONG, b"hi".to_vec())]
+        );
+    }
+
+    #[test]
+    fn limits_the_size_of_whole_messages() {
+        for frames in [
+            vec![
+                frame(false, OP_TEXT, b"abc"),
+                frame(true, OP_CONTINUATION, b"de"),
+            ],
+            vec![frame(false, OP_TEXT, b"abcde")],
+            vec![frame(true, OP_BINARY, b"abcde")],
+        ] {
+            let e = block_on(async {
+                let (port, _server) = server(frames, 0).await;
+                let mut conn = WsConn::connect(&options(port, "max_message_size=4"))
+                    .await
+                    .unwrap();
+                conn.next_message(Duration::from_secs(5)).await.unwrap_err()
+            });
+            assert!(e.to_string().ends_with("ws: message too large"), "{e}");
+        }
+    }
+}
@@ -0,0 +1,713 @@
+//! TLS over the tokio streams of WASI sockets, with rustls and its pure Rust
+//! crypto provider, as `ring` does not build for WASI without a C toolchain.
+
+use crate::source::timer::parse_interval;
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use hyper::client::connect::{Connected, Connection as HyperConnection};
+use hyper::service::Service;
+use hyper::Uri;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use url::Url;
+
+const DEFAULT_RELOAD: Duration = Duration::from_secs(30);
+
+/// Trusts the Mozilla root certificates.
+pub(crate) fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            Arc::new(
+                ClientConfig::builder_with_provider(Arc::new(rustls_rustcrypto::provider()))
+                    .with_safe_default_protocol_versions()
+                    .expect("the crypto provider supports TLS 1.2 and 1.3")
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+/// Trusts the CAs of a PEM file, or else the Mozilla root certificates, and
+/// presents the certificate of `identity`, a PEM certificate chain and key,
+/// if the server asks for one.
+pub(crate) fn client_config_from(
+    ca: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> TransformerResult<Arc<ClientConfig>> {
+    let (ca, identity) = match (ca, identity) {
+        (None, None) => return Ok(client_config()),
+        pair => pair,
+    };
+    let roots = match ca {
+        Some(path) => load_roots(path)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls_rustcrypto::provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(config_error)?
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| pem_error(cert, e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> TransformerResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(pem_error(path, "no certificate"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> TransformerResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn load_roots(path: &Path) -> TransformerResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| pem_error(path, e))?;
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> TransformerError {
+    TransformerError::new(ErrorKind::Config, format!("tls: {}: {e}", path.display()))
+}
+
+fn config_error(e: rustls::Error) -> TransformerError {
+    TransformerError::new(ErrorKind::Config, format!("tls: {e}")).with_source(e)
+}
+
+/// Opens a TLS session to `host` over `io` and completes the handshake.
+pub(crate) async fn connect<IO: AsyncRead + AsyncWrite + Unpin>(
+    config: Arc<ClientConfig>,
+    host: &str,
+    io: IO,
+) -> TransformerResult<TlsStream<IO>> {
+    let name = ServerName::try_from(host.to_string()).map_err(|e| {
+        TransformerError::new(ErrorKind::Config, format!("tls: {host}: {e}")).with_source(e)
+    })?;
+    let conn = ClientConnection::new(config, name).map_err(config_error)?;
+    let mut stream = TlsStream::new(io, conn.into());
+    std::future::poll_fn(|cx| stream.poll_handshake(cx))
+        .await
+        .map_err(|e| {
+            TransformerError::new(ErrorKind::Connection, format!("tls: {host}: {e}")).with_source(e)
+        })?;
+    Ok(stream)
+}
+
+/// Connects a hyper client over TCP, and over TLS for `https://` URLs.
+#[derive(Clone)]
+pub(crate) struct HttpsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl HttpsConnector {
+    pub fn new(config: Arc<ClientConfig>) -> Self {
+        HttpsConnector { config }
+    }
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = MaybeTlsStream;
+    type Error = TransformerError;
+    type Future = Pin<Box<dyn Future<Output = TransformerResult<MaybeTlsStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<TransformerResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let config = self.config.clone();
+        Box::pin(async move {
+            let tls = uri.scheme_str() == Some("https");
+            let host = uri
+                .host()
+                .ok_or_else(|| {
+                    TransformerError::new(ErrorKind::Config, format!("{uri}: missing host"))
+                })?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+            let tcp = TcpStream::connect((host.as_str(), port)).await?;
+            if tls {
+                Ok(MaybeTlsStream::Tls(Box::new(
+                    connect(config, &host, tcp).await?,
+                )))
+            } else {
+                Ok(MaybeTlsStream::Plain(tcp))
+            }
+        })
+    }
+}
+
+/// A connection of `HttpsConnector`.
+pub(crate) enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl HyperConnection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ServerTlsOptions {
+    /// The PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// The PEM private key of the certificate.
+    pub key: PathBuf,
+    /// The PEM CAs that client certificates must be issued by, if any.
+    pub client_ca: Option<PathBuf>,
+    /// Whether a client may also connect without a certificate.
+    pub client_auth_optional: bool,
+    /// How often the files are checked for a new certificate.
+    pub reload: Duration,
+}
+
+impl ServerTlsOptions {
+    /// Reads `cert=/path/cert.pem&key=/path/key.pem`, with
+    /// `client_ca=/path/ca.pem`, `client_auth=required|optional` and
+    /// `reload=30s`, ignoring other parameters.
+    pub fn parse(url: &Url) -> TransformerResult<Self> {
+        let (mut cert, mut key, mut client_ca) = (None, None, None);
+        let mut client_auth_optional = false;
+        let mut reload = DEFAULT_RELOAD;
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "cert" => cert = Some(PathBuf::from(value.as_ref())),
+                "key" => key = Some(PathBuf::from(value.as_ref())),
+                "client_ca" => client_ca = Some(PathBuf::from(value.as_ref())),
+                "client_auth" => {
+                    client_auth_optional = match value.as_ref() {
+                        "required" => false,
+                        "optional" => true,
+                        _ => {
+                            return Err(TransformerError::new(
+                                ErrorKind::Config,
+                                format!(
+                                    "https: invalid client_auth {value:?}, \
+                                     use required or optional"
+                                ),
+                            ))
+                        }
+                    }
+                }
+                "reload" => reload = parse_interval("https", &value)?,
+                _ => {}
+            }
+        }
+        let missing = |name| {
+            TransformerError::new(
+                ErrorKind::Config,
+                format!("https: missing `{name}` parameter"),
+            )
+        };
+        Ok(ServerTlsOptions {
+            cert: cert.ok_or_else(|| missing("cert"))?,
+            key: key.ok_or_else(|| missing("key"))?,
+            client_ca,
+            client_auth_optional,
+            reload,
+        })
+    }
+
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.cert, &self.key].into_iter().chain(&self.client_ca)
+    }
+
+    fn config(&self) -> TransformerResult<Arc<ServerConfig>> {
+        let provider = Arc::new(rustls_rustcrypto::provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(config_error)?;
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_roots(path)?),
+                    provider,
+                );
+                let verifier = if self.client_auth_optional {
+                    verifier.allow_unauthenticated()
+                } else {
+                    verifier
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| pem_error(path, e))?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)
+            .map_err(|e| pem_error(&self.cert, e))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Completes the server side of TLS sessions, with the certificate the files
+/// held when they were last checked, so it can be renewed without a restart.
+pub(crate) struct Acceptor {
+    opts: ServerTlsOptions,
+    state: Mutex<AcceptorState>,
+}
+
+struct AcceptorState {
+    config: Arc<ServerConfig>,
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+}
+
+impl Acceptor {
+    pub fn new(opts: ServerTlsOptions) -> TransformerResult<Self> {
+        let state = AcceptorState {
+            modified: opts.modified(),
+            config: opts.config()?,
+            checked: Instant::now(),
+        };
+        Ok(Acceptor {
+            opts,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub async fn accept<IO: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        io: IO,
+    ) -> TransformerResult<TlsStream<IO>> {
+        let conn = ServerConnection::new(self.config()).map_err(config_error)?;
+        let mut stream = TlsStream::new(io, conn.into());
+        std::future::poll_fn(|cx| stream.poll_handshake(cx))
+            .await
+            .map_err(|e| {
+                TransformerError::new(ErrorKind::Connection, format!("tls: {e}")).with_source(e)
+            })?;
+        Ok(stream)
+    }
+
+    /// The current config, read again if a file has changed. A certificate
+    /// that fails to load is logged and the previous one kept.
+    fn config(&self) -> Arc<ServerConfig> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.checked.elapsed() >= self.opts.reload {
+            state.checked = Instant::now();
+            let modified = self.opts.modified();
+            if modified != state.modified {
+                match self.opts.config() {
+                    Ok(config) => {
+                        log::info!("https: reloaded {}", self.opts.cert.display());
+                        state.config = config;
+                        state.modified = modified;
+                    }
+                    Err(e) => log::error!("{:?}", e.to_string()),
+                }
+            }
+        }
+        state.config.clone()
+    }
+}
+
+/// A TLS session over a stream, either side of it.
+pub(crate) struct TlsStream<IO> {
+    io: IO,
+    conn: Connection,
+    eof: bool,
+    closing: bool,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> TlsStream<IO> {
+    pub fn new(io: IO, conn: Connection) -> Self {
+        TlsStream {
+            io,
+            conn,
+            eof: false,
+            closing: false,
+        }
+    }
+
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.is_handshaking() {
+            while self.conn.wants_write() {
+                ready!(self.write_io(cx))?;
+            }
+            if self.conn.is_handshaking() && ready!(self.read_io(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        while self.conn.wants_write() {
+            ready!(self.write_io(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads TLS records from the stream into the session.
+    fn read_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut reader = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        let n = match self.conn.read_tls(&mut reader) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            result => result?,
+        };
+        if let Err(e) = self.conn.process_new_packets() {
+            // let the peer know why, e.g. with a bad certificate alert
+            let _ = self.write_io(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Writes pending TLS records of the session to the stream.
+    fn write_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut writer = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        match self.conn.write_tls(&mut writer) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            Ok(0) => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // the peer closed the stream without a close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            while this.conn.wants_write() {
+                ready!(this.write_io(cx))?;
+            }
+            if ready!(this.read_io(cx))? == 0 {
+                this.eof = true;
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.conn.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        let n = this.conn.writer().write(buf)?;
+        // the rest goes out with the next write or flush
+        while this.conn.wants_write() {
+            match this.write_io(cx) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => break,
+            };
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        while this.conn.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.conn.send_close_notify();
+            this.closing = true;
+        }
+        while this.conn.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+/// Lets rustls, which reads and writes synchronously, use an async stream,
+/// with `WouldBlock` for `Pending`.
+struct SyncIo<'a, 'b, IO> {
+    io: &'a mut IO,
+    cx: &'a mut Context<'b>,
+}
+
+impl<IO: AsyncRead + Unpin> Read for SyncIo<'_, '_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> Write for SyncIo<'_, '_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A CA, and a server (for `localhost`) and a client certificate it issued.
+    const FILES: [(&str, &str); 5] = [
+        ("ca.pem", include_str!("../testdata/tls/ca.pem")),
+        ("server.pem", include_str!("../testdata/tls/server.pem")),
+        ("server.key", include_str!("../testdata/tls/server.key")),
+        ("client.pem", include_str!("../testdata/tls/client.pem")),
+        ("client.key", include_str!("../testdata/tls/client.key")),
+    ];
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn files() -> PathBuf {
+        let dir = PathBuf::from("target/test-tls");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, pem) in FILES {
+            std::fs::write(dir.join(name), pem).unwrap();
+        }
+        dir
+    }
+
+    fn server_options(query: &str) -> ServerTlsOptions {
+        let dir = files();
+        let url = format!(
+            "https://0.0.0.0:3344?cert={}&key={}&{query}",
+            dir.join("server.pem").display(),
+            dir.join("server.key").display(),
+        );
+        ServerTlsOptions::parse(&Url::parse(&url).unwrap()).unwrap()
+    }
+
+    /// Connects a client to an acceptor and has each side send a line. In
+    /// TLS 1.3 the client learns that its certificate was refused only
+    /// once it reads, so this fails on any error of either side.
+    fn exchange(
+        client: Arc<ClientConfig>,
+        acceptor: &Acceptor,
+    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        block_on(async {
+            let (client_io, server_io) = tokio::io::duplex(4096);
+            let client = async {
+                let mut stream = connect(client, "localhost", client_io)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                stream
+                    .write_all(b"ping\n")
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut reply = vec![0; 5];
+                stream
+                    .read_exact(&mut reply)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>(reply)
+            };
+            let server = async {
+                let mut stream = acceptor
+                    .accept(server_io)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut request = vec![0; 5];
+                stream
+                    .read_exact(&mut request)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                stream
+                    .write_all(b"pong\n")
+                    .await
+                    .map_err(|e| e.to_string())?;
+                stream.flush().await.map_err(|e| e.to_string())?;
+                Ok::<_, String>(request)
+            };
+            let (reply, request) = tokio::join!(client, server);
+            Ok((request?, reply?))
+        })
+    }
+
+    #[test]
+    fn parses_server_options() {
+        let opts = server_options("client_ca=/ca.pem&client_auth=optional&reload=5s&other=1");
+        assert_eq!(opts.client_ca, Some(PathBuf::from("/ca.pem")));
+        assert!(opts.client_auth_optional);
+        assert_eq!(opts.reload, Duration::from_secs(5));
+        let opts = server_options("");
+        assert_eq!(
+            (opts.client_ca, opts.client_auth_optional, opts.reload),
+            (None, false, DEFAULT_RELOAD)
+        );
+
+        let parse = |url| ServerTlsOptions::parse(&Url::parse(url).unwrap());
+        for url in [
+            "https://h?cert=c.pem",
+            "https://h?cert=c.pem&key=k.pem&client_auth=sometimes",
+        ] {
+            assert_eq!(parse(url).unwrap_err().kind(), ErrorKind::Config, "{url}");
+        }
+    }
+
+    #[test]
+    fn exchanges_data_with_a_trusted_server() {
+        let dir = files();
+        let acceptor = Acceptor::new(server_options("")).unwrap();
+        let client = client_config_from(Some(&dir.join("ca.pem")), None).unwrap();
+        let (request, reply) = exchange(client, &acceptor).unwrap();
+        assert_eq!((&request[..], &reply[..]), (&b"ping\n"[..], &b"pong\n"[..]));
+    }
+
+    #[test]
+    fn rejects_an_untrusted_server() {
+        let acceptor = Acceptor::new(server_options("")).unwrap();
+        assert!(exchange(client_config(), &acceptor).is_err());
+    }
+
+    #[test]
+    fn checks_client_certificates() {
+        let dir = files();
+        let ca = dir.join("ca.pem");
+        let identity = (dir.join("client.pem"), dir.join("client.key"));
+        let with_identity =
+            client_config_from(Some(&ca), Some((&identity.0, &identity.1))).unwrap();
+        let anonymous = client_config_from(Some(&ca), None).unwrap();
+
+        let required = format!("client_ca={}", ca.display());
+        let acceptor = Acceptor::new(server_options(&required)).unwrap();
+        assert!(exchange(with_identity.clone(), &acceptor).is_ok());
+        assert!(exchange(anonymous.clone(), &acceptor).is_err());
+
+        let optional = format!("{required}&client_auth=optional");
+        let acceptor = Acceptor::new(server_options(&optional)).unwrap();
+        assert!(exchange(with_identity, &acceptor).is_ok());
+        assert!(exchange(anonymous, &acceptor).is_ok());
+    }
+
+    #[test]
+    fn reports_unreadable_pem_files() {
+        let missing = Path::new("target/test-tls/missing.pem");
+        let e = client_config_from(Some(missing), None).unwrap_err();
+        assert!(e.to_string().contains("target/test-tls/missing.pem"), "{e}");
+        assert_eq!(e.kind(), ErrorKind::Config);
+        let dir = files();
+        let mut opts = server_options("");
+        opts.cert = dir.join("server.key");
+        let e = Acceptor::new(opts).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+}
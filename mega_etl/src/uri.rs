@@ -0,0 +1,47 @@
+//! Helpers for reading the options of source and sink URIs.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use percent_encoding::percent_decode_str;
+
+/// Percent-decodes a part of a URI, e.g. a user name or a password.
+pub(crate) fn decode(value: &str) -> TransformerResult<String> {
+    Ok(percent_decode_str(value)
+        .decode_utf8()
+        .map_err(|e| TransformerError::new(ErrorKind::Config, e.to_string()).with_source(e))?
+        .into_owned())
+}
+
+/// Parses the value of a query parameter, with `scheme` naming the URI in
+/// the error message.
+pub(crate) fn parse_param<V: std::str::FromStr>(
+    scheme: &str,
+    key: &str,
+    value: &str,
+) -> TransformerResult<V> {
+    value.parse().map_err(|_| {
+        TransformerError::new(
+            ErrorKind::Config,
+            format!("{scheme}: invalid {key} {value:?}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(decode("p%40ss%2Fword").unwrap(), "p@ss/word");
+        assert_eq!(decode("plain").unwrap(), "plain");
+        assert_eq!(decode("%ff").unwrap_err().kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn parses_params() {
+        assert_eq!(parse_param::<u16>("mqtt", "keep_alive", "30").unwrap(), 30);
+        let e = parse_param::<u16>("mqtt", "keep_alive", "-1").unwrap_err();
+        assert!(e.to_string().ends_with("mqtt: invalid keep_alive \"-1\""));
+        assert_eq!(e.kind(), ErrorKind::Config);
+    }
+}
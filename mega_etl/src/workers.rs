@@ -0,0 +1,280 @@
+//! Runs records on a bounded number of tasks. Records with the same key still
+//! run one after the other, in the order they came, and the position up to
+//! which all records are done is what a source commits. A record that failed
+//! is never done, so nothing from it on is committed.
+
+use crate::{ErrorKind, TransformerError, TransformerResult};
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// Whether a record was handled, once it ran.
+type Done = Shared<BoxFuture<'static, bool>>;
+
+pub(crate) struct Workers<P> {
+    permits: Arc<Semaphore>,
+    /// The latest record of each key, which the next one with the key waits
+    /// for.
+    last: HashMap<String, Done>,
+    /// The records not committed yet, in the order they came.
+    pending: VecDeque<(P, Done)>,
+    /// The error of the first record that failed.
+    failure: Arc<Mutex<Option<TransformerError>>>,
+}
+
+impl<P> Workers<P> {
+    /// With one worker, the records run one at a time, in order.
+    pub fn new(workers: usize) -> Self {
+        Workers {
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+            last: HashMap::new(),
+            pending: VecDeque::new(),
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts `work` once a worker is free, which is what this waits for, so
+    /// a source reads no further than its workers keep up with. The work is
+    /// left out when the record before it with the same key failed, as it
+    /// would be handled out of order.
+    pub async fn spawn<F>(&mut self, key: Option<String>, position: P, work: F)
+    where
+        F: Future<Output = TransformerResult<()>> + Send + 'static,
+    {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let previous = key.as_ref().and_then(|key| self.last.get(key).cloned());
+        let task = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                if !previous.await {
+                    return Ok(false);
+                }
+            }
+            let result = work.await;
+            drop(permit);
+            result.map(|()| true)
+        });
+        let failure = self.failure.clone();
+        let done = async move {
+            let error = match task.await {
+                Ok(Ok(done)) => return done,
+                Ok(Err(e)) => e,
+                Err(e) => TransformerError::new(ErrorKind::Transform, format!("worker: {e}")),
+            };
+            failure
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get_or_insert(error);
+            false
+        }
+        .boxed()
+        .shared();
+        if let Some(key) = key {
+            self.last.insert(key, done.clone());
+        }
+        self.pending.push_back((position, done));
+    }
+
+    /// Waits for all records, and gives the position of the last one that
+    /// is done along with all those before it.
+    pub async fn finish(&mut self) -> Option<P> {
+        let mut position = None;
+        let mut failed = false;
+        while let Some((next, done)) = self.pending.pop_front() {
+            failed |= !done.await;
+            if !failed {
+                position = Some(next);
+            }
+        }
+        self.last.clear();
+        position
+    }
+
+    /// The position of the latest record that is done along with all those
+    /// before it, if that moved since the last call.
+    pub fn committable(&mut self) -> Option<P> {
+        let mut position = None;
+        while let Some((_, done)) = self.pending.front() {
+            // one that failed stays in front, so it holds back the rest
+            if done.clone().now_or_never() != Some(true) {
+                break;
+            }
+            position = self.pending.pop_front().map(|(position, _)| position);
+        }
+        // the ones that failed still hold back the next with their key
+        self.last
+            .retain(|_, done| done.clone().now_or_never() != Some(true));
+        position
+    }
+
+    /// The error of the first record that failed, once.
+    pub fn failure(&mut self) -> Option<TransformerError> {
+        self.failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[test]
+    fn keeps_the_order_of_records_with_a_key() {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let last = block_on(async {
+            let mut workers = Workers::new(4);
+            for i in 0..8u64 {
+                let key = if i % 2 == 0 { "even" } else { "odd" };
+                let done = done.clone();
+                // the first records of a key take the longest
+                let work = async move {
+                    tokio::time::sleep(Duration::from_millis(40 - i * 5)).await;
+                    done.lock().unwrap().push((key, i));
+                    Ok(())
+                };
+                workers.spawn(Some(key.to_string()), i, work).await;
+            }
+            workers.finish().await
+        });
+        assert_eq!(last, Some(7));
+        let done = done.lock().unwrap();
+        for key in ["even", "odd"] {
+            let order: Vec<_> = done
+                .iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, i)| *i)
+                .collect();
+            assert!(order.windows(2).all(|pair| pair[0] < pair[1]), "{order:?}");
+        }
+    }
+
+    #[test]
+    fn runs_no_more_records_at_once_than_workers() {
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        block_on(async {
+            let mut workers = Workers::new(3);
+            for i in 0..12 {
+                let (running, most) = (running.clone(), most.clone());
+                let work = async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                };
+                workers.spawn(None, i, work).await;
+            }
+            workers.finish().await
+        });
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn commits_records_once_all_before_them_are_done() {
+        block_on(async {
+            let mut workers = Workers::new(4);
+            let (first_done, first) = oneshot::channel::<()>();
+            workers
+                .spawn(None, 1, async move {
+                    let _ = first.await;
+                    Ok(())
+                })
+                .await;
+            workers.spawn(None, 2, async { Ok(()) }).await;
+            workers.spawn(Some("k".into()), 3, async { Ok(()) }).await;
+            settle().await;
+            assert_eq!(workers.committable(), None);
+
+            first_done.send(()).unwrap();
+            settle().await;
+            assert_eq!(workers.committable(), Some(3));
+            assert_eq!(workers.committable(), None);
+            assert!(workers.last.is_empty());
+            assert_eq!(workers.finish().await, None);
+        });
+    }
+
+    #[test]
+    fn runs_records_one_by_one_with_one_worker() {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        block_on(async {
+            let mut workers = Workers::new(0);
+            for i in 0..4u64 {
+                let done = done.clone();
+                let work = async move {
+                    tokio::time::sleep(Duration::from_millis(20 - i * 5)).await;
+                    done.lock().unwrap().push(i);
+                    Ok(())
+                };
+                workers.spawn(None, i, work).await;
+            }
+            workers.finish().await
+        });
+        assert_eq!(*done.lock().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn never_commits_a_record_that_failed() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        block_on(async {
+            let mut workers = Workers::new(4);
+            let failed = || async { Err(TransformerError::new(ErrorKind::Transform, "failed")) };
+            workers.spawn(None, 1, async { Ok(()) }).await;
+            workers.spawn(Some("k".into()), 2, failed()).await;
+            workers.spawn(None, 3, async { Ok(()) }).await;
+            let ran_after = ran.clone();
+            let after = async move {
+                ran_after.lock().unwrap().push(4);
+                Ok(())
+            };
+            workers.spawn(Some("k".into()), 4, after).await;
+            settle().await;
+            assert_eq!(workers.committable(), Some(1));
+            assert_eq!(workers.committable(), None);
+            assert_eq!(workers.failure().unwrap().kind(), ErrorKind::Transform);
+            assert!(workers.failure().is_none());
+            assert_eq!(workers.finish().await, None);
+        });
+        // not handled before the one with its key that failed
+        assert!(ran.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finishes_with_the_records_before_a_failure() {
+        block_on(async {
+            let mut workers = Workers::new(1);
+            workers.spawn(None, 1, async { Ok(()) }).await;
+            let lost = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+            workers
+                .spawn(None, 2, async move { Err(lost.into()) })
+                .await;
+            workers.spawn(None, 3, async { Ok(()) }).await;
+            assert_eq!(workers.finish().await, Some(1));
+            assert!(workers.failure().unwrap().is_retryable());
+        });
+    }
+}